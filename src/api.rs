@@ -0,0 +1,449 @@
+use log::error;
+use rocket::serde::json::Json;
+use rocket::{get, State};
+use serde::Serialize;
+use std::sync::Arc;
+use ts3_query_api::definitions::Codec;
+use utoipa::ToSchema;
+
+use crate::augmentation::{AugmentationClient, ConnectionState};
+use crate::auth::ApiToken;
+use crate::errors::{Error, ErrorBody};
+use crate::helper::{listen_addresses, ClientMetaData};
+use crate::permissions::{CanViewClient, CanViewTree, Permission};
+use crate::planner::Compaction;
+use crate::servers::Servers;
+use crate::tree::Tree;
+
+// The types below are the stable format of the API. Fields may be added in
+// later versions, but are never renamed or removed within `/api/v1`.
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiServer {
+    /// Id of the virtual server in routes and the `server` parameter.
+    pub id: String,
+    pub vsid: i32,
+    pub name: String,
+    pub welcome_message: String,
+    /// Unix timestamp of the creation of the virtual server.
+    pub created: u64,
+    /// Uptime in seconds.
+    pub uptime: i64,
+    pub max_clients: i32,
+    pub listen: Vec<String>,
+    pub version: String,
+    pub platform: String,
+    pub connection: ConnectionState,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiTree {
+    pub server: String,
+    pub name: String,
+    /// Channels in the order of the TeamSpeak client, parents first.
+    pub channels: Vec<ApiChannel>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiChannel {
+    pub id: i32,
+    pub name: String,
+    /// Id of the parent channel, 0 for top level channels.
+    pub parent_id: i32,
+    /// Number of parents above the channel.
+    pub depth: i32,
+    pub needed_talk_power: i32,
+    /// Key of the augmentation the channel belongs to.
+    pub augmentation: Option<String>,
+    /// Clients in the channel, sorted by talk power and nickname.
+    pub clients: Vec<ApiClient>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiClient {
+    pub id: i32,
+    pub nickname: String,
+    pub channel_id: i32,
+    pub is_query: bool,
+    pub talk_power: i32,
+    pub can_talk: bool,
+    /// UUIDs of the badges, images are served at `/badges/<uuid>`.
+    pub badges: Vec<String>,
+    /// Country as flag emoji.
+    pub country: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiCodec {
+    SpeexNarrowband,
+    SpeexWideband,
+    SpeexUltraWideband,
+    Celt,
+    OpusVoice,
+    OpusMusic,
+    Unknown,
+}
+
+impl From<Codec> for ApiCodec {
+    fn from(codec: Codec) -> Self {
+        match codec {
+            Codec::SpeexNarrowband => ApiCodec::SpeexNarrowband,
+            Codec::SpeexWideband => ApiCodec::SpeexWideband,
+            Codec::SpeexUltraWideband => ApiCodec::SpeexUltraWideband,
+            Codec::Celt => ApiCodec::Celt,
+            Codec::OpusVoice => ApiCodec::OpusVoice,
+            Codec::OpusMusic => ApiCodec::OpusMusic,
+            _ => ApiCodec::Unknown,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiChannelDetails {
+    pub id: i32,
+    pub name: String,
+    pub parent_id: i32,
+    pub topic: String,
+    pub description: String,
+    pub codec: ApiCodec,
+    pub codec_quality: i32,
+    /// `None` if unlimited.
+    pub max_clients: Option<i32>,
+    /// `None` if unlimited.
+    pub max_family_clients: Option<i32>,
+    pub needed_talk_power: i32,
+    pub augmentation: Option<String>,
+    pub clients: Vec<ApiClient>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiClientDetails {
+    pub id: i32,
+    pub nickname: String,
+    pub phonetic_nickname: String,
+    pub description: String,
+    pub myts_id: Option<String>,
+    pub channel_id: i32,
+    pub total_connections: i32,
+    pub database_id: i32,
+    pub version: String,
+    pub platform: String,
+    pub talk_power: i32,
+    /// Only included for tokens whose role has `view_client_ip`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiAugmentation {
+    pub key: String,
+    pub identifier: String,
+    pub parent_id: i32,
+    pub template: String,
+    pub numbering: &'static str,
+    pub numbering_argument: String,
+    pub min_empty: usize,
+    pub max_empty: usize,
+    pub min_instances: usize,
+    pub max_instances: Option<usize>,
+    pub sync: bool,
+    pub compaction: Compaction,
+    /// Channels of the instances, in the order of the TeamSpeak client.
+    pub channels: Vec<i32>,
+    pub at_capacity: bool,
+    /// Channel that was renamed away from the first instance by hand.
+    pub taken_over: Option<i32>,
+}
+
+/// Client of the virtual server given in the `server` parameter, the first
+/// one if it is missing.
+fn server_client(
+    servers: &Servers,
+    server: Option<&str>,
+) -> Result<(String, Arc<AugmentationClient>), Error> {
+    let server = match server {
+        Some(server) => server.to_string(),
+        None => servers
+            .iter()
+            .next()
+            .map(|(server, _)| server.clone())
+            .ok_or(Error::UnknownServer)?,
+    };
+    let client = servers.get(&server).ok_or(Error::UnknownServer)?;
+    Ok((server, client))
+}
+
+async fn tree_of(client: &AugmentationClient) -> Result<Tree, Error> {
+    client
+        .tree()
+        .await
+        .inspect_err(|e| error!("Could not build tree: {e}"))
+}
+
+fn clients_of(tree: &Tree, channel: i32) -> Vec<ApiClient> {
+    tree.clients
+        .get(&channel)
+        .into_iter()
+        .flatten()
+        .map(|client| ApiClient {
+            id: client.id,
+            nickname: client.name.clone(),
+            channel_id: client.channel,
+            is_query: client.is_query,
+            talk_power: client.talk_power,
+            can_talk: client.can_talk,
+            badges: client.badges.clone(),
+            country: client.country.clone(),
+        })
+        .collect()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/server",
+    params(
+        ("server" = Option<String>, Query, description = "Id of the virtual server, the first one if omitted"),
+    ),
+    responses(
+        (status = 200, description = "Virtual server", body = ApiServer),
+        (status = 401, description = "Missing or invalid API token", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Unknown virtual server", body = ErrorBody),
+        (status = 502, description = "ServerQuery failed", body = ErrorBody),
+    ),
+    security(("api_token" = []))
+)]
+#[get("/server?<server>")]
+pub async fn server(
+    _token: ApiToken,
+    _access: CanViewTree,
+    servers: &State<Servers>,
+    server: Option<&str>,
+) -> Result<Json<ApiServer>, Error> {
+    let (id, client) = server_client(servers, server)?;
+    let info = client.client()?.server_info().await?;
+
+    Ok(Json(ApiServer {
+        id,
+        vsid: info.id,
+        name: info.name.to_string(),
+        welcome_message: info.welcome_message.to_string(),
+        created: info.created,
+        uptime: info.uptime as i64,
+        max_clients: info.max_clients,
+        listen: listen_addresses(&info.ip, info.port),
+        version: info.version.to_string(),
+        platform: info.platform.to_string(),
+        connection: client.connection(),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tree",
+    params(
+        ("server" = Option<String>, Query, description = "Id of the virtual server, the first one if omitted"),
+    ),
+    responses(
+        (status = 200, description = "Channel tree", body = ApiTree),
+        (status = 401, description = "Missing or invalid API token", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Unknown virtual server", body = ErrorBody),
+        (status = 502, description = "ServerQuery failed", body = ErrorBody),
+    ),
+    security(("api_token" = []))
+)]
+#[get("/tree?<server>")]
+pub async fn tree(
+    _token: ApiToken,
+    _access: CanViewTree,
+    servers: &State<Servers>,
+    server: Option<&str>,
+) -> Result<Json<ApiTree>, Error> {
+    let (id, client) = server_client(servers, server)?;
+    let tree = tree_of(&client).await?;
+
+    let channels = tree
+        .channel_order
+        .iter()
+        .filter_map(|id| tree.channel_map.get(id))
+        .map(|channel| ApiChannel {
+            id: channel.id,
+            name: channel.name.clone(),
+            parent_id: channel.parent_id,
+            depth: channel.indent_level.get(),
+            needed_talk_power: channel.talk_power,
+            augmentation: channel.augmentation_id.clone(),
+            clients: clients_of(&tree, channel.id),
+        })
+        .collect();
+
+    Ok(Json(ApiTree {
+        server: id,
+        name: tree.server_name.clone(),
+        channels,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/channels/{id}",
+    params(
+        ("id" = i32, Path, description = "Id of the channel"),
+        ("server" = Option<String>, Query, description = "Id of the virtual server, the first one if omitted"),
+    ),
+    responses(
+        (status = 200, description = "Channel", body = ApiChannelDetails),
+        (status = 401, description = "Missing or invalid API token", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Unknown virtual server or channel", body = ErrorBody),
+        (status = 502, description = "ServerQuery failed", body = ErrorBody),
+    ),
+    security(("api_token" = []))
+)]
+#[get("/channels/<id>?<server>")]
+pub async fn channel(
+    _token: ApiToken,
+    _access: CanViewTree,
+    servers: &State<Servers>,
+    id: i32,
+    server: Option<&str>,
+) -> Result<Json<ApiChannelDetails>, Error> {
+    let (_, client) = server_client(servers, server)?;
+    let tree = tree_of(&client).await?;
+    let channel = client.client()?.channel_info(id).await?;
+    // entries of the tree are not `Sync`, so none may be held across an await
+    let entry = tree.channel_map.get(&id).ok_or(Error::UnknownChannel)?;
+
+    Ok(Json(ApiChannelDetails {
+        id,
+        name: entry.name.clone(),
+        parent_id: entry.parent_id,
+        topic: channel.topic.to_string(),
+        description: channel.description.to_string(),
+        codec: channel.codec.into(),
+        codec_quality: channel.codec_quality,
+        max_clients: (channel.max_clients != -1).then_some(channel.max_clients),
+        max_family_clients: (channel.max_family_clients != -1)
+            .then_some(channel.max_family_clients),
+        needed_talk_power: channel.needed_talk_power,
+        augmentation: entry.augmentation_id.clone(),
+        clients: clients_of(&tree, id),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/clients/{id}",
+    params(
+        ("id" = i32, Path, description = "Id of the client"),
+        ("server" = Option<String>, Query, description = "Id of the virtual server, the first one if omitted"),
+    ),
+    responses(
+        (status = 200, description = "Client", body = ApiClientDetails),
+        (status = 401, description = "Missing or invalid API token", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Unknown virtual server or client", body = ErrorBody),
+        (status = 502, description = "ServerQuery failed", body = ErrorBody),
+    ),
+    security(("api_token" = []))
+)]
+#[get("/clients/<id>?<server>")]
+pub async fn client(
+    _token: ApiToken,
+    access: CanViewClient,
+    servers: &State<Servers>,
+    id: i32,
+    server: Option<&str>,
+) -> Result<Json<ApiClientDetails>, Error> {
+    let (_, client) = server_client(servers, server)?;
+    let tree = tree_of(&client).await?;
+    let channel_id = tree
+        .clients
+        .values()
+        .flatten()
+        .find(|c| c.id == id)
+        .ok_or(Error::UnknownClientId)?
+        .channel;
+    let info = client.client()?.client_info(id).await?;
+    let meta_data = ClientMetaData::parse(info.meta_data.as_deref());
+
+    Ok(Json(ApiClientDetails {
+        id,
+        nickname: info.nickname.to_string(),
+        phonetic_nickname: info.nickname_phonetic.to_string(),
+        description: info.description.to_string(),
+        myts_id: meta_data.tag,
+        channel_id,
+        total_connections: info.total_connections,
+        database_id: info.database_id,
+        version: info.version.to_string(),
+        platform: info.platform.to_string(),
+        talk_power: info.talk_power,
+        // the IP is only shown to those allowed to see it
+        ip: access
+            .0
+            .allows(Permission::ViewClientIp)
+            .then(|| info.client_ip.to_string()),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/augmentations",
+    params(
+        ("server" = Option<String>, Query, description = "Id of the virtual server, the first one if omitted"),
+    ),
+    responses(
+        (status = 200, description = "Augmentations", body = Vec<ApiAugmentation>),
+        (status = 401, description = "Missing or invalid API token", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Unknown virtual server", body = ErrorBody),
+        (status = 502, description = "ServerQuery failed", body = ErrorBody),
+    ),
+    security(("api_token" = []))
+)]
+#[get("/augmentations?<server>")]
+pub async fn augmentations(
+    _token: ApiToken,
+    _access: CanViewTree,
+    servers: &State<Servers>,
+    server: Option<&str>,
+) -> Result<Json<Vec<ApiAugmentation>>, Error> {
+    let (_, client) = server_client(servers, server)?;
+    let channels = client.client()?.channel_list().await?;
+    let config = client.config.lock().await;
+
+    let augmentations = config
+        .internal
+        .augmentations
+        .iter()
+        .map(|augmentation| {
+            let instances = channels
+                .iter()
+                .filter(|c| augmentation.owns(c))
+                .map(|c| c.id)
+                .collect::<Vec<_>>();
+            ApiAugmentation {
+                key: augmentation.key(),
+                identifier: augmentation.identifier.clone(),
+                parent_id: augmentation.parent,
+                template: augmentation.template.0.clone(),
+                numbering: augmentation.numbering.kind(),
+                numbering_argument: augmentation.numbering.argument(),
+                min_empty: augmentation.min_empty,
+                max_empty: augmentation.max_empty,
+                min_instances: augmentation.min_instances,
+                max_instances: augmentation.max_instances,
+                sync: augmentation.sync,
+                compaction: augmentation.compaction,
+                at_capacity: augmentation.is_at_capacity(instances.len()),
+                channels: instances,
+                taken_over: augmentation.taken_over,
+            }
+        })
+        .collect();
+
+    Ok(Json(augmentations))
+}
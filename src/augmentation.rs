@@ -1,18 +1,29 @@
 use crate::errors::Error;
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::sync::OnceLock;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
 use std::vec;
 use strsim::jaro;
 use tokio::sync::Mutex;
+use tokio::time::sleep;
 use ts3_query_api::definitions::{ChannelListEntry, ChannelProperty};
 use ts3_query_api::definitions::{ClientProperty, Permission};
-use ts3_query_api::error::QueryError;
 use ts3_query_api::QueryClient;
+use utoipa::ToSchema;
 
-use crate::config::Config;
+use crate::config::{Config, ConnectionConfig};
+use crate::lease::Lease;
+use crate::numbering::NumberingScheme;
+use crate::planner::{self, Action, ChannelRef, Compaction};
+use crate::template::NameTemplate;
+use crate::tree::{build_tree, Tree};
 
+/// Legacy prefix triple, only kept to migrate old state files to name templates.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct AugmentationPrefix {
     pub first: String,
@@ -20,83 +31,417 @@ pub struct AugmentationPrefix {
     pub last: String,
 }
 
+/// Sub-channel recreated below every instance of an augmentation.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SubChannel {
+    pub name: String,
+    /// Index of the parent sub-channel, `None` for direct children of the instance.
+    pub parent: Option<usize>,
+    pub permissions: Vec<Permission>,
+    pub properties: Vec<ChannelProperty>,
+}
+
+/// Channel of a [`ForeignGroup`].
+#[derive(Serialize, ToSchema)]
+pub struct ForeignChannel {
+    pub id: i32,
+    pub name: String,
+}
+
+/// Group of similarly named channels that looks like an augmentation managed by
+/// someone else.
+#[derive(Serialize, ToSchema)]
+pub struct ForeignGroup {
+    pub parent: i32,
+    pub channels: Vec<ForeignChannel>,
+    /// Identifier and template inferred from the names, if they follow the
+    /// usual pattern.
+    pub identifier: Option<String>,
+    pub template: Option<NameTemplate>,
+}
+
+/// Options of an augmentation, sent when augmenting a channel or changing them.
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct AugmentationOptions {
+    pub template: NameTemplate,
+    #[serde(default)]
+    pub numbering: NumberingScheme,
+    #[serde(default = "default_empty")]
+    pub min_empty: usize,
+    #[serde(default = "default_empty")]
+    pub max_empty: usize,
+    #[serde(default)]
+    pub min_instances: usize,
+    #[serde(default)]
+    pub max_instances: Option<usize>,
+    #[serde(default)]
+    pub sync: bool,
+    #[serde(default)]
+    pub compaction: Compaction,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Augmentation {
     pub identifier: String,
     pub parent: i32,
-    pub prefix: AugmentationPrefix,
+    #[serde(default, skip_serializing)]
+    pub prefix: Option<AugmentationPrefix>,
+    #[serde(default)]
+    pub template: NameTemplate,
+    #[serde(default)]
+    pub numbering: NumberingScheme,
+    #[serde(default = "default_empty")]
+    pub min_empty: usize,
+    #[serde(default = "default_empty")]
+    pub max_empty: usize,
+    #[serde(default)]
+    pub min_instances: usize,
+    #[serde(default)]
+    pub max_instances: Option<usize>,
+    /// Push edits of the first instance to all other instances.
+    #[serde(default)]
+    pub sync: bool,
+    #[serde(default)]
+    pub compaction: Compaction,
     pub permissions: Vec<Permission>,
     pub properties: Vec<ChannelProperty>,
+    /// Sub-tree of the augmented channel, parents always precede their children.
+    #[serde(default)]
+    pub sub_channels: Vec<SubChannel>,
+    /// Channels owned by the augmentation, name matches are only adopted while
+    /// recovering at startup.
+    #[serde(default)]
+    pub channels: Vec<i32>,
+    /// Channel that was renamed away from the first instance by hand. The
+    /// group is left alone until the augmentation is removed.
+    #[serde(default)]
+    pub taken_over: Option<i32>,
     #[serde(skip)]
-    pub regex: OnceLock<Regex>,
+    pub regex: OnceLock<Option<Regex>>,
+}
+
+fn default_empty() -> usize {
+    1
+}
+
+/// Time a group has to be left alone before it is compacted on idle.
+const IDLE_COMPACTION: Duration = Duration::from_secs(300);
+
+/// Counts the clients of sub-channels towards all their ancestors, so a channel
+/// is only considered empty if its whole sub-tree is.
+fn count_family_clients(channels: &mut [ChannelListEntry]) {
+    let index = channels
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.id, i))
+        .collect::<HashMap<_, _>>();
+    let own = channels
+        .iter()
+        .map(|c| (c.parent_id, c.total_clients))
+        .collect::<Vec<_>>();
+    for (mut parent, clients) in own.into_iter().filter(|(_, clients)| *clients > 0) {
+        while let Some(&i) = index.get(&parent) {
+            channels[i].total_clients += clients;
+            parent = channels[i].parent_id;
+        }
+    }
+}
+
+/// Whether both lists hold the same items, regardless of their order.
+fn same_items<T: PartialEq>(a: &[T], b: &[T]) -> bool {
+    a.len() == b.len() && a.iter().all(|x| b.contains(x)) && b.iter().all(|x| a.contains(x))
+}
+
+/// Splits an augmentation key into the parent channel and the identifier.
+pub fn split_key(key: &str) -> Option<(i32, &str)> {
+    let (parent, identifier) = key.split_once('/')?;
+    Some((parent.parse().ok()?, identifier))
 }
 
 impl Augmentation {
+    pub fn validate(&self) -> Result<(), Error> {
+        self.numbering.validate()?;
+        self.template.validate(&self.identifier, &self.numbering)?;
+        if self.min_empty == 0 {
+            return Err(Error::Options(
+                "at least one empty channel is required".to_string(),
+            ));
+        }
+        if self.max_empty < self.min_empty {
+            return Err(Error::Options(
+                "maximum of empty channels is below the minimum".to_string(),
+            ));
+        }
+        if let Some(max_instances) = self.max_instances {
+            if max_instances < self.min_instances.max(1) {
+                return Err(Error::Options(
+                    "maximum of instances is below the minimum".to_string(),
+                ));
+            }
+        }
+        if self.is_exhausted(self.target_instances(1, 0) - 1) {
+            return Err(Error::Options(
+                "numbering scheme cannot represent the minimum of channels".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Key identifying the augmentation, identifiers are only unique per parent.
+    pub fn key(&self) -> String {
+        format!("{}/{}", self.parent, self.identifier)
+    }
+
+    /// Whether `channel` looks like an instance, i.e. a sub-channel of the
+    /// recorded parent with a name generated by the template.
+    pub fn matches(&self, channel: &ChannelListEntry) -> bool {
+        channel.parent_id == self.parent && self.is_instance(&channel.name)
+    }
+
+    /// Whether `channel` is an instance owned by the augmentation.
+    pub fn owns(&self, channel: &ChannelListEntry) -> bool {
+        channel.parent_id == self.parent && self.channels.contains(&channel.id)
+    }
+
     pub fn is_instance(&self, channel_name: &str) -> bool {
         self.regex
-            .get_or_init(|| {
-                Regex::new(&format!(
-                    r"^(({pre1})|({pre2})|({pre3})){} [IVXLCDM]+$",
-                    regex::escape(&self.identifier),
-                    pre1 = regex::escape(&self.prefix.first),
-                    pre2 = regex::escape(&self.prefix.middle),
-                    pre3 = regex::escape(&self.prefix.last)
-                ))
-                .unwrap()
-            })
-            .is_match(channel_name)
+            .get_or_init(
+                || match self.template.to_regex(&self.identifier, &self.numbering) {
+                    Ok(regex) => Some(regex),
+                    Err(e) => {
+                        error!(
+                            "Invalid name template for augmentation {}: {e}",
+                            self.identifier
+                        );
+                        None
+                    }
+                },
+            )
+            .as_ref()
+            .is_some_and(|r| r.is_match(channel_name))
+    }
+
+    /// Name of the instance at `index` (0-based) in a group of `total` instances.
+    pub fn instance_name(&self, index: usize, total: usize) -> Result<String, Error> {
+        self.template
+            .render(&self.identifier, &self.numbering, index, total)
+    }
+
+    /// Whether the numbering scheme has no numeral left for another instance.
+    pub fn is_exhausted(&self, instances: usize) -> bool {
+        self.numbering.format(instances + 1).is_none()
+    }
+
+    /// Number of instances the group should have with `occupied` occupied out of
+    /// `current` instances. Occupied instances are never removed.
+    pub fn target_instances(&self, occupied: usize, current: usize) -> usize {
+        let mut total = current
+            .clamp(occupied + self.min_empty, occupied + self.max_empty)
+            .max(self.min_instances);
+        if let Some(max_instances) = self.max_instances {
+            total = total.min(max_instances).max(occupied);
+        }
+        total
+    }
+
+    /// Whether the group cannot grow beyond `instances` channels.
+    pub fn is_at_capacity(&self, instances: usize) -> bool {
+        self.max_instances.is_some_and(|m| instances >= m) || self.is_exhausted(instances)
+    }
+
+    /// Replaces the prefix triple of old state files with the equivalent template.
+    pub fn migrate(&mut self) {
+        if let Some(prefix) = self.prefix.take() {
+            self.template = prefix.into();
+            self.regex = OnceLock::new();
+        }
     }
 
-    pub fn set_prefix(&mut self, prefix: AugmentationPrefix) {
-        self.prefix = prefix;
+    pub fn set_options(&mut self, options: AugmentationOptions) {
+        self.template = options.template;
+        self.numbering = options.numbering;
+        self.min_empty = options.min_empty;
+        self.max_empty = options.max_empty;
+        self.min_instances = options.min_instances;
+        self.max_instances = options.max_instances;
+        self.sync = options.sync;
+        self.compaction = options.compaction;
         self.regex = OnceLock::new();
     }
 }
 
+/// Id, name and client count of every instance as seen by the last reconciliation.
+type Snapshot = Vec<(i32, String, i32)>;
+
+/// Delay before the first reconnect attempt, doubled after every failure.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound for the delay between reconnect attempts.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// State of the ServerQuery connection.
+#[derive(Clone, Copy, Serialize, ToSchema)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connected,
+    Reconnecting { attempt: u32 },
+}
+
 pub struct AugmentationClient {
-    pub client: QueryClient,
+    /// Current connection, replaced whenever the connection is re-established
+    /// and `None` until the first connection succeeded.
+    client: RwLock<Option<Arc<QueryClient>>>,
+    connection: RwLock<ConnectionState>,
     pub config: Mutex<Config>,
+    /// Last snapshot of every augmentation and since when it is unchanged.
+    snapshots: Mutex<HashMap<String, (Snapshot, Instant)>>,
+    /// Random identifier of this Sunbird, used as owner of the lease.
+    instance: String,
+    /// Whether this Sunbird holds the lease on the virtual server.
+    leased: AtomicBool,
 }
 
 impl AugmentationClient {
-    pub async fn new() -> Result<Self, Error> {
-        let config = Config::read_config()?;
+    /// Connects to the virtual server `vsid` of the connection `name`. The
+    /// client starts on standby, augmentations are only recovered once
+    /// [`renew_lease`](Self::renew_lease) acquires the lease. In `dry_run`
+    /// mode the server is only inspected and left untouched.
+    ///
+    /// If the server cannot be reached, the client is returned disconnected
+    /// and connects once [`reconnect`](Self::reconnect) succeeds.
+    pub async fn new(dry_run: bool, name: &str, vsid: i32) -> Result<Self, Error> {
+        let config = Config::read_config(name, vsid)?;
+
+        let ret = Self {
+            client: RwLock::new(None),
+            connection: RwLock::new(ConnectionState::Reconnecting { attempt: 1 }),
+            config: Mutex::new(config),
+            snapshots: Mutex::new(HashMap::new()),
+            instance: format!("{:016x}", rand::random::<u64>()),
+            leased: AtomicBool::new(false),
+        };
+
+        let connected = async {
+            ret.try_connect().await?;
+            if !dry_run {
+                ret.register().await?;
+            }
+            Ok::<_, Error>(())
+        }
+        .await;
+        match connected {
+            Ok(()) => *ret.connection.write().unwrap() = ConnectionState::Connected,
+            // a dry run only inspects the server once
+            Err(e) if dry_run => return Err(e),
+            Err(e) => {
+                warn!(
+                    "Could not connect to virtual server {}, retrying in the background: {e}",
+                    Config::server_id(name, vsid)
+                );
+                *ret.client.write().unwrap() = None;
+                return Ok(ret);
+            }
+        }
+
+        if dry_run {
+            return Ok(ret);
+        }
 
         info!(
-            "Connecting to server {}:{}",
-            config.external.host, config.external.port
+            "Successfully connected to TeamSpeak server query for virtual server {}",
+            Config::server_id(name, vsid)
         );
 
+        // find potential augmentations managed by another instance
+        let groups = match ret.foreign_groups().await {
+            Ok(groups) => groups,
+            Err(e) => {
+                warn!("Could not look for foreign augmentations: {e}");
+                Vec::new()
+            }
+        };
+        groups.iter().for_each(|g| {
+            warn!(
+                "Found potential augmentations: {}",
+                g.channels
+                    .iter()
+                    .map(|c| c.name.clone())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        });
+        if !groups.is_empty() {
+            warn!("Please ensure that Marungu Sunbird is only run once per virtual server. If there are any additional instances, please remove them. If you believe this warning is a false positive, you may safely ignore this warning.");
+        }
+
+        Ok(ret)
+    }
+
+    /// Current connection to the server.
+    pub fn client(&self) -> Result<Arc<QueryClient>, Error> {
+        self.client
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or(Error::Disconnected)
+    }
+
+    pub fn connection(&self) -> ConnectionState {
+        *self.connection.read().unwrap()
+    }
+
+    /// Channels and clients as shown in the web interface.
+    pub async fn tree(&self) -> Result<Tree, Error> {
+        let config = self.config.lock().await;
+        build_tree(&self.client()?, &config.internal.augmentations).await
+    }
+
+    async fn connect(config: &ConnectionConfig, vsid: i32) -> Result<QueryClient, Error> {
+        info!("Connecting to server {}:{}", config.host, config.port);
+
         let client = QueryClient::connect(
-            (config.external.host.clone(), config.external.port),
-            &config.external.user,
-            &config.external.pass,
+            (config.host.clone(), config.port),
+            &config.user,
+            &config.pass,
         )
         .await?;
 
-        info!("Using virtual server {}", config.external.vsid);
+        info!("Using virtual server {vsid}");
 
-        client.use_sid(config.external.vsid).await?;
+        client.use_sid(vsid).await?;
+        Ok(client)
+    }
 
-        client
+    /// Sets the nickname and registers for events on a fresh connection.
+    async fn register(&self) -> Result<(), Error> {
+        // the nickname may still be used by a Sunbird whose lease expired
+        if let Err(e) = self
+            .client()?
             .client_update(&[ClientProperty::Nickname("Marungu Sunbird".to_string())])
-            .await?;
+            .await
+        {
+            warn!("Could not set nickname: {e}");
+        }
 
         info!("Registering for events");
 
-        client.server_notify_register_all().await?;
-
-        let ret = Self {
-            client,
-            config: Mutex::new(config),
-        };
-
-        info!("Recovering augmentations from state.bin");
+        self.client()?.server_notify_register_all().await?;
+        Ok(())
+    }
 
-        let config = ret.config.lock().await;
-        for augmentation in config.internal.augmentations.iter() {
-            ret.recover_augmentation(augmentation).await?;
+    async fn recover_augmentations(&self) -> Result<(), Error> {
+        let mut config = self.config.lock().await;
+        info!("Recovering augmentations of virtual server {}", config.vsid);
+        for augmentation in config.internal.augmentations.iter_mut() {
+            if augmentation.taken_over.is_some() {
+                warn!(
+                    "Augmentation {} was taken over, not recovering it",
+                    augmentation.identifier
+                );
+                continue;
+            }
+            self.recover_augmentation(augmentation).await?;
         }
+        config.write_internal_config()?;
 
         info!(
             "Successfully recovered {} augmentation{}",
@@ -106,19 +451,167 @@ impl AugmentationClient {
                 _ => "s",
             }
         );
-        drop(config);
+        Ok(())
+    }
 
-        // find potential augmentations managed by another instance
-        let channels = ret.client.channel_list().await?;
+    /// Re-establishes the connection with exponential backoff and recovers all
+    /// augmentations once it is back, unless another Sunbird took over the
+    /// virtual server in the meantime.
+    pub async fn reconnect(&self) {
+        let mut delay = RECONNECT_DELAY;
+        let mut attempt = 1;
+        loop {
+            *self.connection.write().unwrap() = ConnectionState::Reconnecting { attempt };
+            match self.try_reconnect().await {
+                Ok(()) => break,
+                Err(e) => warn!(
+                    "Reconnect attempt {attempt} failed: {e}, retrying in {}s",
+                    delay.as_secs()
+                ),
+            }
+            sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            attempt += 1;
+        }
+        *self.connection.write().unwrap() = ConnectionState::Connected;
+        info!("Successfully reconnected to TeamSpeak server query");
+    }
+
+    /// Opens a new connection, replacing the current one.
+    async fn try_connect(&self) -> Result<(), Error> {
+        let client = {
+            let config = self.config.lock().await;
+            Self::connect(&config.connection, config.vsid).await?
+        };
+        *self.client.write().unwrap() = Some(Arc::new(client));
+        Ok(())
+    }
+
+    async fn try_reconnect(&self) -> Result<(), Error> {
+        self.try_connect().await?;
+
+        // the lease was lost together with the old connection
+        self.snapshots.lock().await.clear();
+        self.leased.store(false, Ordering::Relaxed);
+        self.register().await?;
+        self.renew_lease().await?;
+        Ok(())
+    }
+
+    /// Active lease of another Sunbird on the virtual server, if any.
+    async fn foreign_lease(&self) -> Result<Option<Lease>, Error> {
+        let clients = self.client()?.client_list().await?;
+        for client in clients.into_iter().filter(|c| c.is_query) {
+            let info = self.client()?.client_info(client.id).await?;
+            if let Some(lease) = Lease::parse(&info.description) {
+                if lease.owner != self.instance && !lease.is_expired() {
+                    return Ok(Some(lease));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    async fn write_lease(&self, lease: Option<Lease>) -> Result<(), Error> {
+        let description = lease.map(|l| l.to_string()).unwrap_or_default();
+        self.client()?
+            .client_update(&[ClientProperty::Description(description)])
+            .await?;
+        Ok(())
+    }
+
+    /// Acquires or renews the lease on the virtual server, unless another
+    /// Sunbird holds an active one. Returns whether this Sunbird holds it.
+    ///
+    /// All augmentations are recovered when the lease is acquired, if that
+    /// fails the lease is only considered held after the next renewal.
+    pub async fn renew_lease(&self) -> Result<bool, Error> {
+        if let Some(lease) = self.foreign_lease().await? {
+            if self.leased.swap(false, Ordering::Relaxed) {
+                warn!(
+                    "Lost the lease on the virtual server to Sunbird {}",
+                    lease.owner
+                );
+                self.write_lease(None).await?;
+            }
+            return Ok(false);
+        }
+
+        self.write_lease(Some(Lease::new(&self.instance))).await?;
+        // two Sunbirds may have written their lease at once, the smaller one wins
+        if let Some(lease) = self.foreign_lease().await? {
+            if lease.owner < self.instance {
+                self.write_lease(None).await?;
+                return Ok(false);
+            }
+        }
+
+        if !self.leased.load(Ordering::Relaxed) {
+            info!("Acquired the lease on the virtual server");
+            // the server may have changed while another Sunbird managed it
+            self.snapshots.lock().await.clear();
+            self.recover_augmentations().await?;
+            self.leased.store(true, Ordering::Relaxed);
+        }
+        Ok(true)
+    }
+
+    fn ensure_lease(&self) -> Result<(), Error> {
+        if self.leased.load(Ordering::Relaxed) {
+            Ok(())
+        } else {
+            Err(Error::Leased)
+        }
+    }
+
+    /// Pokes the only client called `nickname` with `message` and returns its id.
+    pub async fn poke_client(&self, nickname: &str, message: &str) -> Result<i32, Error> {
+        let clients = self.client()?.client_list().await?;
+        let mut matching = clients
+            .iter()
+            .filter(|c| !c.is_query && c.nickname == nickname);
+        let (Some(client), None) = (matching.next(), matching.next()) else {
+            return Err(Error::UnknownClient);
+        };
+        self.client()?.client_poke(client.id, message).await?;
+        Ok(client.id)
+    }
+
+    /// Web roles granted to the client `id` by its server groups.
+    pub async fn client_roles(&self, id: i32, nickname: &str) -> Result<Vec<String>, Error> {
+        let info = self.client()?.client_info(id).await?;
+        // the id may belong to someone else by now
+        if info.nickname != nickname {
+            return Err(Error::UnknownClient);
+        }
+        let config = self.config.lock().await;
+        Ok(config.connection.group_roles.roles(&info.server_groups))
+    }
+
+    /// Groups similarly named sub-channels of the same parent that are not
+    /// owned by any augmentation.
+    pub async fn foreign_groups(&self) -> Result<Vec<ForeignGroup>, Error> {
+        let channels = self.client()?.channel_list().await?;
         let pot_augmentation_regex = Regex::new(r"^.*[IVXLCDM]+$")?;
 
+        let config = self.config.lock().await;
+        let known = |channel: &ChannelListEntry| {
+            config
+                .internal
+                .augmentations
+                .iter()
+                .any(|a| a.owns(channel) || a.taken_over == Some(channel.id))
+        };
+
         // group channel by string similarity
         let mut groups: Vec<Vec<&ChannelListEntry>> = vec![];
         for channel in channels.iter() {
-            if pot_augmentation_regex.is_match(&channel.name) {
+            if pot_augmentation_regex.is_match(&channel.name) && !known(channel) {
                 let mut found = false;
                 for group in groups.iter_mut() {
-                    if jaro(&group[group.len() - 1].name, &channel.name) > 0.8 {
+                    let last = group[group.len() - 1];
+                    if last.parent_id == channel.parent_id && jaro(&last.name, &channel.name) > 0.8
+                    {
                         group.push(channel);
                         found = true;
                         break;
@@ -129,37 +622,31 @@ impl AugmentationClient {
                 }
             }
         }
+        drop(config);
 
-        let config = ret.config.lock().await;
-        // check if there are any groups with more than one channel that are not augmented by this instance
-        let groups = groups
+        Ok(groups
             .into_iter()
             .filter(|g| g.len() > 1)
-            .filter(|g| {
-                !config
-                    .internal
-                    .augmentations
-                    .iter()
-                    .any(|a| a.is_instance(&g[0].name))
+            .map(|g| {
+                let names = g.iter().map(|c| c.name.as_str()).collect::<Vec<_>>();
+                let (identifier, template) = match NameTemplate::infer(&names) {
+                    Some((identifier, template)) => (Some(identifier), Some(template)),
+                    None => (None, None),
+                };
+                ForeignGroup {
+                    parent: g[0].parent_id,
+                    channels: g
+                        .iter()
+                        .map(|c| ForeignChannel {
+                            id: c.id,
+                            name: c.name.clone(),
+                        })
+                        .collect(),
+                    identifier,
+                    template,
+                }
             })
-            .collect::<Vec<_>>();
-
-        drop(config);
-
-        groups.iter().for_each(|g| {
-            warn!(
-                "Found potential augmentations: {}",
-                g.iter()
-                    .map(|c| c.name.clone())
-                    .collect::<Vec<_>>()
-                    .join(", ")
-            );
-        });
-        if !groups.is_empty() {
-            warn!("Please ensure that Marungu Sunbird is only run once per virtual server. If there are any additional instances, please remove them. If you believe this warning is a false positive, you may safely ignore this warning.");
-        }
-
-        Ok(ret)
+            .collect())
     }
 
     async fn create_channel(
@@ -167,7 +654,7 @@ impl AugmentationClient {
         name: &str,
         properties: &[ChannelProperty],
         permissions: &[Permission],
-    ) -> Result<i32, QueryError> {
+    ) -> Result<i32, Error> {
         let mut properties = properties.to_vec();
         let mut icon = None;
         if let Some(index) = properties
@@ -177,16 +664,16 @@ impl AugmentationClient {
             icon = Some(properties.remove(index));
         }
 
-        let channel = self.client.channel_create(name, &properties).await?;
+        let channel = self.client()?.channel_create(name, &properties).await?;
 
         if let Some(icon) = icon {
-            self.client.channel_edit(channel, &[icon]).await?;
+            self.client()?.channel_edit(channel, &[icon]).await?;
         }
 
         debug!("Created channel {channel}");
 
         if !permissions.is_empty() {
-            self.client
+            self.client()?
                 .channel_add_perm_multiple(channel, permissions)
                 .await?;
         }
@@ -197,7 +684,7 @@ impl AugmentationClient {
         &self,
         channel: &ChannelListEntry,
         mut properties: Vec<ChannelProperty>,
-    ) -> Result<(), QueryError> {
+    ) -> Result<(), Error> {
         // remove properties that are already set
         properties.retain(|p| match p {
             ChannelProperty::Name(name) => *name != channel.name,
@@ -205,8 +692,222 @@ impl AugmentationClient {
             _ => true,
         });
         if !properties.is_empty() {
-            self.client.channel_edit(channel.id, &properties).await?;
+            self.client()?.channel_edit(channel.id, &properties).await?;
+        }
+        Ok(())
+    }
+
+    /// Lists all channels, counting the clients of sub-channels towards their
+    /// parents.
+    async fn channel_list(&self) -> Result<Vec<ChannelListEntry>, Error> {
+        let mut channels = self.client()?.channel_list().await?;
+        count_family_clients(&mut channels);
+        Ok(channels)
+    }
+
+    /// Captures the sub-tree below `channel`, so it can be recreated below new
+    /// instances.
+    async fn read_sub_channels(
+        &self,
+        channels: &[ChannelListEntry],
+        channel: i32,
+    ) -> Result<Vec<SubChannel>, Error> {
+        let mut sub_channels = Vec::new();
+        let mut pending = vec![(channel, None)];
+        while let Some((id, parent)) = pending.pop() {
+            for child in channels.iter().filter(|c| c.parent_id == id) {
+                let (permissions, properties) = self.read_template(child.id).await?;
+                sub_channels.push(SubChannel {
+                    name: child.name.clone(),
+                    parent,
+                    permissions,
+                    properties,
+                });
+                pending.push((child.id, Some(sub_channels.len() - 1)));
+            }
+        }
+        Ok(sub_channels)
+    }
+
+    /// Creates an instance after the channel `after` including its sub-tree.
+    async fn create_instance(
+        &self,
+        augmentation: &Augmentation,
+        name: &str,
+        after: Option<i32>,
+    ) -> Result<i32, Error> {
+        let mut props = augmentation.properties.clone();
+        if augmentation.parent != 0 {
+            props.push(ChannelProperty::ParentId(augmentation.parent));
+        }
+        if let Some(after) = after {
+            props.push(ChannelProperty::Order(after));
+        }
+        let instance = self
+            .create_channel(name, &props, &augmentation.permissions)
+            .await?;
+
+        let mut created = Vec::new();
+        // last created sub-channel for every parent, to keep the order
+        let mut last = HashMap::new();
+        for sub_channel in augmentation.sub_channels.iter() {
+            let parent = match sub_channel.parent {
+                Some(index) => *created.get(index).ok_or(Error::NotFound)?,
+                None => instance,
+            };
+            let mut props = sub_channel.properties.clone();
+            props.push(ChannelProperty::ParentId(parent));
+            if let Some(after) = last.get(&sub_channel.parent) {
+                props.push(ChannelProperty::Order(*after));
+            }
+            let id = self
+                .create_channel(&sub_channel.name, &props, &sub_channel.permissions)
+                .await?;
+            last.insert(sub_channel.parent, id);
+            created.push(id);
+        }
+
+        Ok(instance)
+    }
+
+    /// Reads the permissions and properties of a channel that are copied to
+    /// every instance.
+    async fn read_template(
+        &self,
+        channel: i32,
+    ) -> Result<(Vec<Permission>, Vec<ChannelProperty>), Error> {
+        // find all permissions of the channel
+        let permissions = self.client()?.channel_perm_list(channel).await?;
+        let permissions = permissions.into_iter().map(|p| p.perm).collect::<Vec<_>>();
+
+        // find all channel properties
+        let info = self.client()?.channel_info(channel).await?;
+        let mut properties = info.to_properties_vec();
+        properties.retain(|p| {
+            !matches!(
+                p,
+                ChannelProperty::Name(_)
+                    | ChannelProperty::ParentId(_)
+                    | ChannelProperty::Order(_)
+                    | ChannelProperty::FlagDefault(_)
+                    | ChannelProperty::Password(_)
+            )
+        });
+
+        Ok((permissions, properties))
+    }
+
+    /// Stores the template of the first instance and applies whatever differs to
+    /// the other instances. Unless `force` is set, the instances are only touched
+    /// if the first instance differs from the stored template.
+    ///
+    /// Permissions removed from the first instance are revoked from the others.
+    ///
+    /// Returns whether the stored template changed.
+    async fn sync_template(
+        &self,
+        augmentation: &mut Augmentation,
+        channels: &[ChannelListEntry],
+        force: bool,
+    ) -> Result<bool, Error> {
+        let instances = self.get_augmentation_instances(augmentation, channels);
+        let Some((first, others)) = instances.split_first() else {
+            return Ok(false);
+        };
+
+        let (permissions, properties) = self.read_template(first.id).await?;
+        // the query lists permissions and properties in no particular order
+        let changed = !same_items(&permissions, &augmentation.permissions)
+            || !same_items(&properties, &augmentation.properties);
+        if !changed && !force {
+            return Ok(false);
+        }
+
+        for channel in others {
+            let (channel_permissions, channel_properties) = self.read_template(channel.id).await?;
+            let missing_properties = properties
+                .iter()
+                .filter(|p| !channel_properties.contains(p))
+                .cloned()
+                .collect::<Vec<_>>();
+            if !missing_properties.is_empty() {
+                self.client()?
+                    .channel_edit(channel.id, &missing_properties)
+                    .await?;
+            }
+            let missing_permissions = permissions
+                .iter()
+                .filter(|p| !channel_permissions.contains(p))
+                .cloned()
+                .collect::<Vec<_>>();
+            if !missing_permissions.is_empty() {
+                self.client()?
+                    .channel_add_perm_multiple(channel.id, &missing_permissions)
+                    .await?;
+            }
+            // permissions only differing in their value are overwritten above
+            for permission in channel_permissions.iter().filter(|p| {
+                !permissions
+                    .iter()
+                    .any(|t| mem::discriminant(*p) == mem::discriminant(t))
+            }) {
+                self.client()?
+                    .channel_del_perm(channel.id, permission)
+                    .await?;
+            }
+            debug!("Synced template of channel {}", channel.id);
         }
+
+        augmentation.permissions = permissions;
+        augmentation.properties = properties;
+        Ok(changed)
+    }
+
+    /// Pushes edits of the first instance of augmentations in sync mode to the
+    /// other instances.
+    pub async fn sync_templates(&self) -> Result<(), Error> {
+        if self.ensure_lease().is_err() {
+            return Ok(());
+        }
+        let channels = self.client()?.channel_list().await?;
+        let mut config = self.config.lock().await;
+        let mut changed = false;
+        for augmentation in config
+            .internal
+            .augmentations
+            .iter_mut()
+            .filter(|a| a.sync && a.taken_over.is_none())
+        {
+            changed |= self.sync_template(augmentation, &channels, false).await?;
+        }
+        if changed {
+            config.write_internal_config()?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies the template of the first instance to all instances, regardless
+    /// of the sync mode.
+    pub async fn resync_augmentation(&self, key: &str) -> Result<(), Error> {
+        self.ensure_lease()?;
+        let channels = self.client()?.channel_list().await?;
+        let mut config = self.config.lock().await;
+        let augmentation = config
+            .internal
+            .augmentations
+            .iter_mut()
+            .find(|a| a.key() == key)
+            .ok_or(Error::NotFound)?;
+        if augmentation.taken_over.is_some() {
+            return Err(Error::Options(
+                "augmentation was taken over and is no longer managed".to_string(),
+            ));
+        }
+        if self.sync_template(augmentation, &channels, true).await? {
+            config.write_internal_config()?;
+        }
+
         Ok(())
     }
 
@@ -217,313 +918,260 @@ impl AugmentationClient {
     ) -> Vec<&ChannelListEntry> {
         channels
             .iter()
-            .filter(|c| augmentation.is_instance(&c.name))
+            .filter(|c| augmentation.owns(c))
             .collect::<Vec<_>>()
     }
 
-    fn get_empty_instances<'a>(
-        &'a self,
-        channels: &'a [&ChannelListEntry],
-    ) -> Vec<&&ChannelListEntry> {
-        channels
-            .iter()
-            .filter(|c: &&&ts3_query_api::definitions::ChannelListEntry| c.total_clients == 0)
-            .collect::<Vec<_>>()
-    }
+    /// Reconciles the augmentations whose instances changed since the last call.
+    ///
+    /// If the first instance was deleted by hand the group is repaired, if it
+    /// was renamed the augmentation is flagged as taken over instead.
+    pub async fn update_augmented_channels(&self) -> Result<(), Error> {
+        // another Sunbird manages the virtual server
+        if self.ensure_lease().is_err() {
+            debug!("Not holding the lease, skipping reconciliation");
+            return Ok(());
+        }
+        let channels = self.channel_list().await?;
+        let mut config = self.config.lock().await;
+        let mut snapshots = self.snapshots.lock().await;
+        let mut changed = false;
+        for augmentation in config.internal.augmentations.iter_mut() {
+            if augmentation.taken_over.is_some() {
+                continue;
+            }
 
-    pub async fn update_augmented_channels(&self) -> Result<(), QueryError> {
-        let channels = self.client.channel_list().await?;
-        for augmentation in self.config.lock().await.internal.augmentations.iter() {
-            // ensure there is always exactly one empty channel with the name
-            // "<channel> <n>", where <n> is a roman numeral
+            // forget the instances that were deleted by hand
+            let owned = augmentation.channels.len();
+            augmentation
+                .channels
+                .retain(|id| channels.iter().any(|c| c.id == *id));
+            changed |= augmentation.channels.len() != owned;
 
-            // find all channels with the name and any number of the augmented channel
             let augmentation_instances = self.get_augmentation_instances(augmentation, &channels);
 
-            // if there is no channel, ignore it with warning
-            if augmentation_instances.is_empty() {
-                warn!("Channel {} not found", augmentation.identifier);
+            // skip augmentations that were not touched since the last pass, unless
+            // they are due for compaction
+            let snapshot = augmentation_instances
+                .iter()
+                .map(|c| (c.id, c.name.clone(), c.total_clients))
+                .collect::<Snapshot>();
+            let previous = snapshots.remove(&augmentation.key());
+            let unchanged = previous.as_ref().is_some_and(|(s, _)| *s == snapshot);
+            let since = match &previous {
+                Some((_, since)) if unchanged => *since,
+                _ => Instant::now(),
+            };
+            snapshots.insert(augmentation.key(), (snapshot, since));
+            let compact = augmentation
+                .compaction
+                .compacts(since.elapsed() >= IDLE_COMPACTION);
+            if unchanged && !(compact && planner::has_gaps(&augmentation_instances)) {
                 continue;
             }
 
-            // count empty channels
-            let empty_channels = self.get_empty_instances(&augmentation_instances);
-
-            // everything is fine if there is either
-            //  * exactly one empty channel and it is the last one or
-            //  * only two empty channels
-            if empty_channels.len() == 1
-                && empty_channels[0].id == augmentation_instances.last().unwrap().id
-                || empty_channels.len() == 2 && augmentation_instances.len() == 2
+            // the first instance was renamed and is no instance anymore
+            if let Some(first) = augmentation_instances
+                .first()
+                .filter(|c| !augmentation.is_instance(&c.name))
             {
+                warn!(
+                    "Augmentation {} was taken over by channel {}",
+                    augmentation.identifier, first.id
+                );
+                augmentation.taken_over = Some(first.id);
+                augmentation.channels.retain(|id| *id != first.id);
+                snapshots.remove(&augmentation.key());
+                changed = true;
                 continue;
             }
 
-            if empty_channels.len() > 2 {
-                // this should never happen, warn and ignore
-                warn!(
-                    "More than 2 empty channels found for augmentation {}",
-                    augmentation.identifier
-                );
+            // if there is no channel and there never was one, ignore it with warning
+            if augmentation_instances.is_empty() && owned == 0 {
+                warn!("Channel {} not found", augmentation.identifier);
+                continue;
             }
 
-            if empty_channels.len() > 1 {
-                // cleanup required, move and rename channels
-                let target_order = empty_channels[0].order;
-                let target_name = empty_channels[0].name.clone();
-                // if empty channel is the second to last overall, simply remove the last one
-                if empty_channels[0].id
-                    == augmentation_instances[augmentation_instances.len() - 2].id
-                {
-                    self.client
-                        .channel_delete(empty_channels[empty_channels.len() - 1].id, false)
-                        .await?;
-                    // rename empty channel to have the last prefix
-                    self.change_properties(
-                        empty_channels[0],
-                        vec![ChannelProperty::Name(format!(
-                            "{}{} {}",
-                            augmentation.prefix.last,
-                            augmentation.identifier,
-                            roman::to(augmentation_instances.len() as i32 - 1).unwrap()
-                        ))],
-                    )
-                    .await?;
-                } else {
-                    // delete the empty channel
-                    self.client
-                        .channel_delete(empty_channels[0].id, false)
-                        .await?;
-                    // move the pre to last (presumably non empty) channel to the empty channel
-                    let mut replacement_name = augmentation_instances
-                        [augmentation_instances.len() - 2]
-                        .name
-                        .clone();
-                    replacement_name.replace_range(
-                        0..augmentation.prefix.middle.len(),
-                        &augmentation.prefix.last,
-                    );
-                    self.client
-                        .channel_edit(
-                            augmentation_instances[augmentation_instances.len() - 2].id,
-                            &[
-                                ChannelProperty::Order(target_order),
-                                ChannelProperty::Name(target_name),
-                            ],
-                        )
-                        .await?;
-                    // rename the last (presumably empty) channel
-                    self.client
-                        .channel_edit(
-                            augmentation_instances[augmentation_instances.len() - 1].id,
-                            &[ChannelProperty::Name(replacement_name)],
-                        )
-                        .await?;
+            // otherwise the plan recreates what was deleted
+            let result = match planner::plan(augmentation, &augmentation_instances, compact) {
+                Ok(plan) => {
+                    changed |= !plan.is_empty();
+                    self.execute_plan(augmentation, &plan).await
                 }
-            } else if empty_channels.is_empty() {
-                // simply add a new empty channel
-                let mut props = augmentation.properties.clone();
-                props.push(ChannelProperty::Order(
-                    augmentation_instances.last().unwrap().id,
-                ));
-                self.create_channel(
-                    &format!(
-                        "{}{} {}",
-                        augmentation.prefix.last,
-                        augmentation.identifier,
-                        match roman::to(augmentation_instances.len() as i32 + 1) {
-                            Some(r) => r,
-                            None => {
-                                warn!(
-                                    "Could not convert {} to roman numeral",
-                                    augmentation_instances.len() + 1
-                                );
-                                (augmentation_instances.len() + 1).to_string()
-                            }
-                        }
-                    ),
-                    &props,
-                    augmentation.permissions.as_ref(),
-                )
-                .await?;
-                // rename the last channel to have the middle prefix
-                self.change_properties(
-                    augmentation_instances.last().unwrap(),
-                    vec![ChannelProperty::Name(format!(
-                        "{}{} {}",
-                        augmentation.prefix.middle,
-                        augmentation.identifier,
-                        match roman::to(augmentation_instances.len() as i32) {
-                            Some(r) => r,
-                            None => {
-                                warn!(
-                                    "Could not convert {} to roman numeral",
-                                    augmentation_instances.len()
-                                );
-                                augmentation_instances.len().to_string()
-                            }
-                        }
-                    ))],
-                )
-                .await?;
-            } else {
-                // move all clients from the last channel to the empty channel
-                // get all clients from the last channel
-                let clients = self.client.client_list().await?;
-                let clients = clients
-                    .into_iter()
-                    .filter(|c| c.channel_id == augmentation_instances.last().unwrap().id)
-                    .map(|c| c.id)
-                    .collect::<Vec<_>>();
-                // move all clients to the empty channel
-                self.client
-                    .client_move(&clients, empty_channels[0].id, None, true)
-                    .await?;
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                // look at the augmentation again on the next pass
+                snapshots.remove(&augmentation.key());
+                config.write_internal_config()?;
+                return Err(e);
             }
         }
+        if changed {
+            config.write_internal_config()?;
+        }
 
         Ok(())
     }
 
-    pub async fn recover_augmentation(&self, augmentation: &Augmentation) -> Result<(), Error> {
-        let channels = self.client.channel_list().await?;
+    /// Adopts the channels matching the name template in addition to the owned
+    /// ones and brings the group into shape.
+    pub async fn recover_augmentation(&self, augmentation: &mut Augmentation) -> Result<(), Error> {
+        let channels = self.channel_list().await?;
 
-        let augmentation_instances = self.get_augmentation_instances(augmentation, &channels);
+        let augmentation_instances = self.recover_instances(augmentation, &channels);
 
-        // if there is no channel, create one
-        if augmentation_instances.is_empty() {
-            self.create_channel(
-                &format!("{}{} I", augmentation.prefix.first, augmentation.identifier),
-                &augmentation.properties,
-                &augmentation.permissions,
-            )
-            .await?;
-            self.create_channel(
-                &format!("{}{} II", augmentation.prefix.last, augmentation.identifier),
-                &augmentation.properties,
-                &augmentation.permissions,
-            )
-            .await?;
-            return Ok(());
-        }
+        // if there is no channel, the plan creates the whole group, idle groups
+        // are compacted by the next passes
+        let compact = augmentation.compaction.compacts(false);
+        let plan = planner::plan(augmentation, &augmentation_instances, compact)?;
+        self.execute_plan(augmentation, &plan).await
+    }
 
-        let empty_channels = self.get_empty_instances(&augmentation_instances);
+    /// Claims the channels owned by or named like the instances of `augmentation`,
+    /// so instances are found again after the server or the state file changed.
+    fn recover_instances<'a>(
+        &'a self,
+        augmentation: &mut Augmentation,
+        channels: &'a [ChannelListEntry],
+    ) -> Vec<&ChannelListEntry> {
+        let augmentation_instances = channels
+            .iter()
+            .filter(|c| augmentation.owns(c) || augmentation.matches(c))
+            .collect::<Vec<_>>();
+        augmentation.channels = augmentation_instances.iter().map(|c| c.id).collect();
+        augmentation_instances
+    }
 
-        let mut offset = 2;
-        if augmentation_instances.len() > empty_channels.len() {
-            offset = 1;
-        }
-        // delete all but the last empty channel
-        let mut remaining_channels = augmentation_instances.clone();
-        if empty_channels.len() > offset {
-            for channel in empty_channels[..empty_channels.len() - offset].iter() {
-                self.client.channel_delete(channel.id, false).await?;
-                remaining_channels.retain(|c| c.id != channel.id);
-            }
+    /// Plans the reconciliation of an augmentation without touching the server.
+    pub async fn plan_augmentation(&self, key: &str) -> Result<Vec<Action>, Error> {
+        let config = self.config.lock().await;
+        let augmentation = config
+            .internal
+            .augmentations
+            .iter()
+            .find(|a| a.key() == key)
+            .ok_or(Error::NotFound)?;
+        if augmentation.taken_over.is_some() {
+            return Ok(Vec::new());
         }
+        let channels = self.channel_list().await?;
+        let augmentation_instances = self.get_augmentation_instances(augmentation, &channels);
+        let compact = augmentation.compaction.compacts(false);
+        planner::plan(augmentation, &augmentation_instances, compact)
+    }
 
-        // rename all other channels to the correct roman numeral
-        for (i, channel) in remaining_channels.iter().enumerate() {
-            let mut prefix = augmentation.prefix.middle.as_str();
-            if i == 0 {
-                prefix = augmentation.prefix.first.as_str();
-            } else if i == remaining_channels.len() - 1 {
-                prefix = augmentation.prefix.last.as_str();
+    /// Logs the plans of all augmentations without applying them.
+    pub async fn log_plans(&self) -> Result<(), Error> {
+        let channels = self.channel_list().await?;
+        let config = self.config.lock().await;
+        for augmentation in config.internal.augmentations.iter() {
+            if let Some(channel) = augmentation.taken_over {
+                info!(
+                    "Augmentation {} was taken over by channel {channel}",
+                    augmentation.identifier
+                );
+                continue;
             }
-            let name = format!(
-                "{}{} {}",
-                prefix,
-                augmentation.identifier,
-                match roman::to(i as i32 + 1) {
-                    Some(r) => r,
-                    None => {
-                        warn!("Could not convert {} to roman numeral", i + 1);
-                        (i + 1).to_string()
-                    }
-                }
-            );
-            self.change_properties(channel, vec![ChannelProperty::Name(name)])
-                .await?;
-        }
-        // if the remaining empty channel is not the last one, move all clients from the last one to the empty one
-        if !empty_channels.is_empty() {
-            if empty_channels[empty_channels.len() - 1].id
-                != augmentation_instances.last().unwrap().id
-            {
-                // get all clients from the last channel
-                let clients = self.client.client_list().await?;
-                let clients = clients
-                    .into_iter()
-                    .filter(|c| c.id == augmentation_instances.last().unwrap().id)
-                    .map(|c| c.id)
-                    .collect::<Vec<_>>();
-                // move all clients to the empty channel
-                self.client
-                    .client_move(
-                        &clients,
-                        empty_channels[empty_channels.len() - 1].id,
-                        None,
-                        true,
-                    )
-                    .await?;
+            // a real start recovers the augmentations before reconciling them
+            let mut augmentation = augmentation.clone();
+            let augmentation_instances = self.recover_instances(&mut augmentation, &channels);
+            let compact = augmentation.compaction.compacts(false);
+            let plan = planner::plan(&augmentation, &augmentation_instances, compact)?;
+            if plan.is_empty() {
+                info!("Augmentation {} is up to date", augmentation.identifier);
+                continue;
             }
-        } else {
-            // create a new empty channel
-            let mut props = augmentation.properties.clone();
-            props.push(ChannelProperty::Order(
-                augmentation_instances.last().unwrap().id,
-            ));
+            info!("Plan for augmentation {}:", augmentation.identifier);
+            for action in plan {
+                info!("  {action}");
+            }
+        }
+        Ok(())
+    }
 
-            self.create_channel(
-                &format!(
-                    "{}{} {}",
-                    augmentation.prefix.last,
-                    augmentation.identifier,
-                    match roman::to(augmentation_instances.len() as i32 + 1) {
-                        Some(r) => r,
-                        None => {
-                            warn!(
-                                "Could not convert {} to roman numeral",
-                                augmentation_instances.len() + 1
-                            );
-                            (augmentation_instances.len() + 1).to_string()
-                        }
+    /// Applies `plan` and keeps track of the created and deleted instances.
+    async fn execute_plan(
+        &self,
+        augmentation: &mut Augmentation,
+        plan: &[Action],
+    ) -> Result<(), Error> {
+        let mut created = Vec::new();
+        let resolve = |channel: &ChannelRef, created: &[i32]| match channel {
+            ChannelRef::Existing(id) => Ok(*id),
+            ChannelRef::Created(index) => created.get(*index).copied().ok_or(Error::NotFound),
+        };
+
+        for action in plan {
+            debug!("Executing {action}");
+            match action {
+                Action::Create { name, after } => {
+                    let after = match after {
+                        Some(after) => Some(resolve(after, &created)?),
+                        None => None,
+                    };
+                    let channel = self.create_instance(augmentation, name, after).await?;
+                    augmentation.channels.push(channel);
+                    created.push(channel);
+                }
+                Action::Rename { channel, to, .. } => {
+                    self.client()?
+                        .channel_edit(*channel, &[ChannelProperty::Name(to.clone())])
+                        .await?;
+                }
+                Action::Reorder { channel, after } => {
+                    self.client()?
+                        .channel_edit(*channel, &[ChannelProperty::Order(*after)])
+                        .await?;
+                }
+                Action::MoveClients { from, to } => {
+                    // include the clients of the whole sub-tree
+                    let channels = self.client()?.channel_list().await?;
+                    let mut family = vec![*from];
+                    let mut i = 0;
+                    while i < family.len() {
+                        let parent = family[i];
+                        family.extend(
+                            channels
+                                .iter()
+                                .filter(|c| c.parent_id == parent)
+                                .map(|c| c.id),
+                        );
+                        i += 1;
                     }
-                ),
-                &props,
-                &augmentation.permissions,
-            )
-            .await?;
-            // rename the last channel to have the middle prefix
-            self.change_properties(
-                augmentation_instances.last().unwrap(),
-                vec![ChannelProperty::Name(format!(
-                    "{}{} {}",
-                    augmentation.prefix.middle,
-                    augmentation.identifier,
-                    match roman::to(augmentation_instances.len() as i32) {
-                        Some(r) => r,
-                        None => {
-                            warn!(
-                                "Could not convert {} to roman numeral",
-                                augmentation_instances.len()
-                            );
-                            augmentation_instances.len().to_string()
-                        }
+                    let clients = self
+                        .client()?
+                        .client_list()
+                        .await?
+                        .into_iter()
+                        .filter(|c| family.contains(&c.channel_id))
+                        .map(|c| c.id)
+                        .collect::<Vec<_>>();
+                    if !clients.is_empty() {
+                        self.client()?
+                            .client_move(&clients, *to, None, true)
+                            .await?;
                     }
-                ))],
-            )
-            .await?;
+                }
+                Action::Delete { channel, .. } => {
+                    self.client()?.channel_delete(*channel, false).await?;
+                    augmentation.channels.retain(|id| id != channel);
+                }
+            }
         }
 
         Ok(())
     }
 
+    /// Augments the channel named by the identifier of `key` below its parent.
     pub async fn add_augmentation(
         &self,
-        identifier: &str,
-        prefix: AugmentationPrefix,
+        key: &str,
+        options: AugmentationOptions,
     ) -> Result<(), Error> {
+        self.ensure_lease()?;
+        let (parent, identifier) = split_key(key).ok_or(Error::NotFound)?;
+
         // ensure there are no overlaps in the augmented channels
         if self
             .config
@@ -532,46 +1180,51 @@ impl AugmentationClient {
             .internal
             .augmentations
             .iter()
-            .any(|c| c.identifier == identifier)
+            .any(|c| c.key() == key)
         {
-            return Err(Error::NotFound);
+            return Err(Error::AlreadyAugmented);
         }
 
-        let channels = self.client.channel_list().await?;
-        // find a channel with the name <identifier>
+        let channels = self.client()?.channel_list().await?;
+        // find a channel with the name <identifier> below the parent
         let channel = channels
             .iter()
-            .find(|c| c.name == identifier)
+            .find(|c| c.name == identifier && c.parent_id == parent)
             .ok_or(Error::NotFound)?;
 
-        // find all permissions of the channel
-        let permissions = self.client.channel_perm_list(channel.id).await?;
-        let mut permissions = permissions.into_iter().map(|p| p.perm).collect::<Vec<_>>();
+        let (mut permissions, properties) = self.read_template(channel.id).await?;
+        let sub_channels = self.read_sub_channels(&channels, channel.id).await?;
         permissions.push(Permission::i_channel_needed_modify_power(100));
         permissions.push(Permission::i_channel_needed_permission_modify_power(100));
 
-        // find all channel properties
-        let info = self.client.channel_info(channel.id).await?;
-        let mut properties = info.to_properties_vec();
-        properties.retain(|p| {
-            !matches!(
-                p,
-                ChannelProperty::Name(_)
-                    | ChannelProperty::Order(_)
-                    | ChannelProperty::FlagDefault(_)
-                    | ChannelProperty::Password(_)
-            )
-        });
+        let mut augmentation = Augmentation {
+            identifier: identifier.to_string(),
+            parent: channel.parent_id,
+            prefix: None,
+            template: options.template,
+            numbering: options.numbering,
+            min_empty: options.min_empty,
+            max_empty: options.max_empty,
+            min_instances: options.min_instances,
+            max_instances: options.max_instances,
+            sync: options.sync,
+            compaction: options.compaction,
+            permissions,
+            properties,
+            sub_channels,
+            channels: vec![channel.id],
+            taken_over: None,
+            regex: OnceLock::new(),
+        };
+        augmentation.validate()?;
 
+        let total = augmentation.target_instances(1, 0);
         self.change_properties(
             channel,
-            vec![ChannelProperty::Name(format!(
-                "{}{} I",
-                prefix.first, identifier,
-            ))],
+            vec![ChannelProperty::Name(augmentation.instance_name(0, total)?)],
         )
         .await?;
-        self.client
+        self.client()?
             .channel_add_perm_multiple(
                 channel.id,
                 &[
@@ -581,67 +1234,117 @@ impl AugmentationClient {
             )
             .await?;
 
-        let mut props = properties.clone();
-        props.push(ChannelProperty::Order(channel.id));
+        let mut previous = channel.id;
+        for i in 1..total {
+            previous = self
+                .create_instance(
+                    &augmentation,
+                    &augmentation.instance_name(i, total)?,
+                    Some(previous),
+                )
+                .await?;
+            augmentation.channels.push(previous);
+        }
+
+        self.config.lock().await.add_augmentation(augmentation)?;
 
-        self.create_channel(
-            &format!("{}{} II", prefix.last, identifier,),
-            &props,
-            &permissions,
-        )
-        .await?;
+        Ok(())
+    }
+
+    /// Adopts the foreign group containing `channel` as augmentation. The
+    /// channels are kept as they are, the next reconciliation brings the group
+    /// into shape.
+    pub async fn adopt_group(&self, channel: i32) -> Result<(), Error> {
+        self.ensure_lease()?;
+        let group = self
+            .foreign_groups()
+            .await?
+            .into_iter()
+            .find(|g| g.channels.iter().any(|c| c.id == channel))
+            .ok_or(Error::NotFound)?;
+        // the first instance serves as template, whichever channel was given
+        let first = group.channels[0].id;
+        let (Some(identifier), Some(template)) = (group.identifier, group.template) else {
+            return Err(Error::Template(
+                "could not infer the name template of the group".to_string(),
+            ));
+        };
+
+        let key = format!("{}/{identifier}", group.parent);
+        if self
+            .config
+            .lock()
+            .await
+            .internal
+            .augmentations
+            .iter()
+            .any(|a| a.key() == key)
+        {
+            return Err(Error::AlreadyAugmented);
+        }
+
+        let channels = self.client()?.channel_list().await?;
+        let (mut permissions, properties) = self.read_template(first).await?;
+        let sub_channels = self.read_sub_channels(&channels, first).await?;
+        permissions.push(Permission::i_channel_needed_modify_power(100));
+        permissions.push(Permission::i_channel_needed_permission_modify_power(100));
 
         let augmentation = Augmentation {
-            identifier: identifier.to_string(),
-            parent: channel.parent_id,
-            prefix: prefix.clone(),
+            identifier,
+            parent: group.parent,
+            prefix: None,
+            template,
+            numbering: NumberingScheme::Roman,
+            min_empty: default_empty(),
+            max_empty: default_empty(),
+            min_instances: 0,
+            max_instances: None,
+            sync: false,
+            compaction: Compaction::default(),
             permissions,
             properties,
+            sub_channels,
+            channels: group.channels.iter().map(|c| c.id).collect(),
+            taken_over: None,
             regex: OnceLock::new(),
         };
+        augmentation.validate()?;
 
-        self.config
-            .lock()
-            .await
-            .add_augmentation(augmentation.clone())?;
+        info!(
+            "Adopting {} channels as augmentation {}",
+            augmentation.channels.len(),
+            augmentation.identifier
+        );
+        self.config.lock().await.add_augmentation(augmentation)?;
 
         Ok(())
     }
 
-    pub async fn remove_augmentation(&self, identifier: &str) -> Result<(), Error> {
+    pub async fn remove_augmentation(&self, key: &str) -> Result<(), Error> {
+        self.ensure_lease()?;
         let mut augmentation = self.config.lock().await;
-        let augmentation = augmentation.remove_augmentation(identifier)?;
-        let channels = self.client.channel_list().await?;
+        let mut augmentation = augmentation.remove_augmentation(key)?;
+        self.snapshots.lock().await.remove(key);
+        let channels = self.channel_list().await?;
         let augmentation_instances = self.get_augmentation_instances(&augmentation, &channels);
-        // move all users to the first channel
-        let clients = self.client.client_list().await?;
-        let clients = clients
-            .into_iter()
-            .filter(|c| {
-                augmentation_instances[1..]
-                    .iter()
-                    .any(|a| a.id == c.channel_id)
-            })
-            .map(|c| c.id)
-            .collect::<Vec<_>>();
-        if !clients.is_empty() {
-            self.client
-                .client_move(&clients, augmentation_instances[0].id, None, true)
-                .await?;
-        }
-        // delete all other channels
-        for channel in augmentation_instances[1..].iter() {
-            self.client.channel_delete(channel.id, false).await?;
+
+        // the first channel belongs to someone else now, only clean up the spares
+        if augmentation.taken_over.is_some() {
+            let plan = planner::plan_cleanup(&augmentation_instances);
+            return self.execute_plan(&mut augmentation, &plan).await;
         }
+
+        // move all users to the first channel, delete all other channels and
         // rename the first channel
-        self.change_properties(
-            augmentation_instances[0],
-            vec![ChannelProperty::Name(identifier.to_string())],
-        )
-        .await?;
-        self.client
+        let plan = planner::plan_abridge(&augmentation, &augmentation_instances);
+        self.execute_plan(&mut augmentation, &plan).await?;
+
+        let Some(first) = augmentation_instances.first() else {
+            return Ok(());
+        };
+        self.client()?
             .channel_add_perm_multiple(
-                augmentation_instances[0].id,
+                first.id,
                 &[
                     Permission::i_channel_needed_modify_power(75),
                     Permission::i_channel_needed_permission_modify_power(75),
@@ -652,47 +1355,42 @@ impl AugmentationClient {
         Ok(())
     }
 
-    pub async fn change_augmentation_prefix(
+    pub async fn change_augmentation_options(
         &self,
-        identifier: &str,
-        prefix: AugmentationPrefix,
+        key: &str,
+        options: AugmentationOptions,
     ) -> Result<(), Error> {
+        self.ensure_lease()?;
         let mut config = self.config.lock().await;
         let augmentation = match config
             .internal
             .augmentations
             .iter_mut()
-            .find(|a| a.identifier == identifier)
+            .find(|a| a.key() == key)
         {
             Some(a) => a,
             None => return Err(Error::NotFound),
         };
-        let channels = self.client.channel_list().await?;
+        if augmentation.taken_over.is_some() {
+            return Err(Error::Options(
+                "augmentation was taken over and is no longer managed".to_string(),
+            ));
+        }
+        let channels = self.channel_list().await?;
         let augmentation_instances = self.get_augmentation_instances(augmentation, &channels);
-        // rename all channels
-        for (i, channel) in augmentation_instances.iter().enumerate() {
-            let mut local_prefix = prefix.middle.as_str();
-            if i == 0 {
-                local_prefix = prefix.first.as_str();
-            } else if i == augmentation_instances.len() - 1 {
-                local_prefix = prefix.last.as_str();
-            }
-            let name = format!(
-                "{}{} {}",
-                local_prefix,
-                augmentation.identifier,
-                match roman::to(i as i32 + 1) {
-                    Some(r) => r,
-                    None => {
-                        warn!("Could not convert {} to roman numeral", i + 1);
-                        (i + 1).to_string()
-                    }
-                }
-            );
-            self.change_properties(channel, vec![ChannelProperty::Name(name)])
-                .await?;
+
+        let mut updated = augmentation.clone();
+        updated.set_options(options);
+        updated.validate()?;
+
+        // rename all channels and apply the new number of empty channels
+        if !augmentation_instances.is_empty() {
+            let compact = updated.compaction.compacts(false);
+            let plan = planner::plan(&updated, &augmentation_instances, compact)?;
+            self.execute_plan(&mut updated, &plan).await?;
         }
-        augmentation.set_prefix(prefix);
+        *augmentation = updated;
+        config.write_internal_config()?;
 
         Ok(())
     }
@@ -0,0 +1,515 @@
+use base64::{engine::general_purpose, Engine as _};
+use indexmap::IndexMap;
+use pbkdf2::pbkdf2_hmac;
+use rand::Rng;
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// Identifier of the hashing scheme at the start of every password hash.
+const SCHEME: &str = "pbkdf2-sha256";
+/// PBKDF2 rounds used for new password hashes.
+const ROUNDS: u32 = 600_000;
+/// Most PBKDF2 rounds accepted from a stored hash, so a hand-edited config
+/// cannot stall a login for minutes.
+const MAX_ROUNDS: u32 = 10_000_000;
+/// Hash of an unknown password, checked for unknown users so that a login
+/// takes as long whether or not the user exists.
+const DUMMY_HASH: &str =
+    "pbkdf2-sha256$600000$UFnzWTC2EAfVw4mYexL44g$Zg/Fp61eizcV4FJSKftIGvHToB8W+CajHMzQKqDhmNM";
+/// Name of the private cookie holding the session.
+const SESSION_COOKIE: &str = "session";
+/// Time a session stays valid after logging in.
+const SESSION_HOURS: i64 = 12;
+/// Name of the private cookie referring to a pending [`Challenge`].
+const CHALLENGE_COOKIE: &str = "challenge";
+/// Time a login code sent via TeamSpeak stays valid.
+const CHALLENGE_SECONDS: i64 = 300;
+/// Wrong codes after which a [`Challenge`] is dropped.
+const CHALLENGE_ATTEMPTS: u32 = 3;
+/// Codes sent per nickname and per address within [`CODE_WINDOW_SECONDS`].
+const CODES_PER_NICKNAME: u32 = 3;
+const CODES_PER_ADDRESS: u32 = 10;
+const CODE_WINDOW_SECONDS: i64 = 900;
+/// Wrong codes per nickname, across all its challenges, after which no
+/// further codes are sent or accepted for [`LOCK_SECONDS`].
+const WRONG_CODES: u32 = 6;
+const LOCK_SECONDS: i64 = 3600;
+/// Failed password logins per user and per address within
+/// [`LOGIN_WINDOW_SECONDS`] before further attempts are refused.
+const LOGIN_FAILURES: u32 = 5;
+const LOGIN_WINDOW_SECONDS: i64 = 900;
+
+/// Local user of the web interface.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct UserConfig {
+    /// Password hash as printed by `--hash-password`.
+    pub password: String,
+    /// Name of a role in the `roles` section of the config.
+    #[serde(default = "default_role")]
+    pub role: String,
+}
+
+fn default_role() -> String {
+    "admin".to_string()
+}
+
+/// Token for the JSON API, sent as `Authorization: Bearer <token>`.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ApiTokenConfig {
+    pub token: String,
+    /// Name of a role in the `roles` section of the config.
+    #[serde(default = "default_api_role")]
+    pub role: String,
+}
+
+fn default_api_role() -> String {
+    "viewer".to_string()
+}
+
+/// Server groups whose members may log in with their TeamSpeak identity,
+/// keyed by the name of the role they get.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(transparent)]
+pub struct GroupRoles(IndexMap<String, Vec<i32>>);
+
+impl GroupRoles {
+    /// Roles granted by any of the `groups`.
+    pub fn roles(&self, groups: &[i32]) -> Vec<String> {
+        self.0
+            .iter()
+            .filter(|(_, ids)| groups.iter().any(|group| ids.contains(group)))
+            .map(|(role, _)| role.clone())
+            .collect()
+    }
+}
+
+/// Hashes `password` with a random salt, in the format stored in the config.
+pub fn hash_password(password: &str) -> String {
+    let salt = rand::random::<[u8; 16]>();
+    let mut hash = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, ROUNDS, &mut hash);
+    format!(
+        "{SCHEME}${ROUNDS}${}${}",
+        general_purpose::STANDARD_NO_PAD.encode(salt),
+        general_purpose::STANDARD_NO_PAD.encode(hash)
+    )
+}
+
+/// Checks `password` against a hash created by [`hash_password`].
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    let mut parts = hash.split('$');
+    if parts.next() != Some(SCHEME) {
+        return false;
+    }
+    let Some(rounds) = parts
+        .next()
+        .and_then(|rounds| rounds.parse::<u32>().ok())
+        .filter(|rounds| (1..=MAX_ROUNDS).contains(rounds))
+    else {
+        return false;
+    };
+    let Some(salt) = parts
+        .next()
+        .and_then(|salt| general_purpose::STANDARD_NO_PAD.decode(salt).ok())
+    else {
+        return false;
+    };
+    let Some(expected) = parts
+        .next()
+        .and_then(|hash| general_purpose::STANDARD_NO_PAD.decode(hash).ok())
+    else {
+        return false;
+    };
+
+    let mut actual = vec![0u8; expected.len()];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, rounds, &mut actual);
+    !expected.is_empty() && constant_time_eq(&actual, &expected)
+}
+
+/// Compares secrets in constant time, to not leak how much of them matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Counts attempts per key and refuses further ones once `limit` attempts
+/// happened within `window` seconds of the first.
+pub struct Throttle {
+    limit: u32,
+    window: i64,
+    /// Attempts and the timestamp of the first one, keyed by who tried.
+    attempts: Mutex<HashMap<String, (u32, i64)>>,
+}
+
+impl Throttle {
+    pub fn new(limit: u32, window: i64) -> Self {
+        Self {
+            limit,
+            window,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `key` may try again.
+    pub fn allows(&self, key: &str) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        match self.attempts.lock().unwrap().get(key) {
+            Some((count, since)) => *count < self.limit || since + self.window <= now,
+            None => true,
+        }
+    }
+
+    pub fn record(&self, key: &str) {
+        let now = chrono::Utc::now().timestamp();
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.retain(|_, (_, since)| *since + self.window > now);
+        attempts.entry(key.to_string()).or_insert((0, now)).0 += 1;
+    }
+
+    pub fn reset(&self, key: &str) {
+        self.attempts.lock().unwrap().remove(key);
+    }
+}
+
+/// Local users allowed to log in.
+pub struct Users {
+    users: IndexMap<String, UserConfig>,
+    /// Failed logins per user and per address.
+    throttle: Throttle,
+}
+
+impl Users {
+    pub fn new(users: IndexMap<String, UserConfig>) -> Self {
+        Self {
+            users,
+            throttle: Throttle::new(LOGIN_FAILURES, LOGIN_WINDOW_SECONDS),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Whether logins for `user` or from `ip` are refused after too many
+    /// failures.
+    pub fn is_throttled(&self, ip: Option<IpAddr>, user: &str) -> bool {
+        !self.throttle.allows(&format!("user:{user}"))
+            || ip.is_some_and(|ip| !self.throttle.allows(&format!("ip:{ip}")))
+    }
+
+    /// Starts a session for `user` if `password` is correct. The hash is
+    /// checked on a blocking thread, as it deliberately takes a while.
+    pub async fn login(
+        &self,
+        cookies: &CookieJar<'_>,
+        ip: Option<IpAddr>,
+        user: &str,
+        password: &str,
+    ) -> bool {
+        let config = self.users.get(user);
+        let hash = config
+            .map_or(DUMMY_HASH, |c| c.password.as_str())
+            .to_string();
+        let password = password.to_string();
+        let valid = tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+            .await
+            .unwrap_or(false)
+            && config.is_some();
+        if !valid {
+            self.throttle.record(&format!("user:{user}"));
+            if let Some(ip) = ip {
+                self.throttle.record(&format!("ip:{ip}"));
+            }
+            return false;
+        }
+        // the address keeps its failures, a single known password must not
+        // clear the way for guessing others
+        self.throttle.reset(&format!("user:{user}"));
+        Session::start(cookies, user, vec![self.users[user].role.clone()], None);
+        true
+    }
+}
+
+/// Session of a logged in user. Routes do not use it directly, but the
+/// permission guards in [`crate::permissions`] built on top of it.
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub user: String,
+    pub roles: Vec<String>,
+    /// Virtual server the session is limited to, all if `None`.
+    pub server: Option<String>,
+    /// Unix timestamp after which the session is no longer valid.
+    expires: i64,
+}
+
+impl Session {
+    pub fn start(cookies: &CookieJar<'_>, user: &str, roles: Vec<String>, server: Option<String>) {
+        let session = Session {
+            user: user.to_string(),
+            roles,
+            server,
+            expires: chrono::Utc::now().timestamp() + SESSION_HOURS * 3600,
+        };
+        let Ok(value) = serde_json::to_string(&session) else {
+            return;
+        };
+        cookies.add_private(
+            Cookie::build((SESSION_COOKIE, value))
+                .http_only(true)
+                .same_site(SameSite::Lax)
+                .max_age(rocket::time::Duration::hours(SESSION_HOURS)),
+        );
+    }
+
+    pub fn end(cookies: &CookieJar<'_>) {
+        cookies.remove_private(SESSION_COOKIE);
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Session {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(mut session) = req
+            .cookies()
+            .get_private(SESSION_COOKIE)
+            .and_then(|cookie| serde_json::from_str::<Session>(cookie.value()).ok())
+        else {
+            return Outcome::Error((Status::Unauthorized, ()));
+        };
+        if session.expires <= chrono::Utc::now().timestamp() {
+            return Outcome::Error((Status::Unauthorized, ()));
+        }
+        let valid = match &session.server {
+            // users removed from the config lose their sessions, changed roles
+            // apply right away
+            None => match req
+                .rocket()
+                .state::<Users>()
+                .and_then(|users| users.users.get(&session.user))
+            {
+                Some(config) => {
+                    session.roles = vec![config.role.clone()];
+                    true
+                }
+                None => false,
+            },
+            // roles from server groups only apply to the server they come from
+            Some(server) => {
+                req.routed_segment(0) != Some("s") || req.routed_segment(1) == Some(server)
+            }
+        };
+        if !valid {
+            return Outcome::Error((Status::Unauthorized, ()));
+        }
+        Outcome::Success(session)
+    }
+}
+
+/// Tokens allowed to use the JSON API, keyed by the name of their owner.
+pub struct ApiTokens(IndexMap<String, ApiTokenConfig>);
+
+impl ApiTokens {
+    pub fn new(tokens: IndexMap<String, ApiTokenConfig>) -> Self {
+        Self(tokens)
+    }
+
+    fn find(&self, token: &str) -> Option<ApiToken> {
+        self.0
+            .iter()
+            .find(|(_, config)| constant_time_eq(config.token.as_bytes(), token.as_bytes()))
+            .map(|(name, config)| ApiToken {
+                name: name.clone(),
+                role: config.role.clone(),
+            })
+    }
+}
+
+/// Client of the JSON API. As request guard it fails with 401 unless the
+/// request carries a configured token, the permissions of its role apply
+/// through the guards in [`crate::permissions`].
+pub struct ApiToken {
+    pub name: String,
+    pub role: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiToken {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .and_then(|token| req.rocket().state::<ApiTokens>()?.find(token.trim()));
+        match token {
+            Some(token) => Outcome::Success(token),
+            None => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Login code sent to a TeamSpeak client, waiting to be entered in the browser.
+pub struct Challenge {
+    pub server: String,
+    /// Id of the client the code was sent to.
+    pub client: i32,
+    pub nickname: String,
+    code: String,
+    expires: i64,
+    attempts: u32,
+}
+
+/// Pending TeamSpeak logins and the limits keeping them from being abused to
+/// poke clients or to guess codes.
+pub struct Challenges {
+    /// Challenges keyed by the token in the challenge cookie.
+    pending: Mutex<HashMap<String, Challenge>>,
+    /// Codes sent per nickname.
+    nickname_codes: Throttle,
+    /// Codes requested per address.
+    address_codes: Throttle,
+    /// Wrong codes entered per nickname.
+    wrong_codes: Throttle,
+}
+
+impl Default for Challenges {
+    fn default() -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+            nickname_codes: Throttle::new(CODES_PER_NICKNAME, CODE_WINDOW_SECONDS),
+            address_codes: Throttle::new(CODES_PER_ADDRESS, CODE_WINDOW_SECONDS),
+            wrong_codes: Throttle::new(WRONG_CODES, LOCK_SECONDS),
+        }
+    }
+}
+
+/// Key of a nickname in the throttles, nicknames are only unique per server.
+fn nickname_key(server: &str, nickname: &str) -> String {
+    format!("nickname:{server}/{nickname}")
+}
+
+impl Challenges {
+    /// Random six digit login code.
+    pub fn code() -> String {
+        format!("{:06}", rand::thread_rng().gen_range(0..1_000_000))
+    }
+
+    /// Counts a request for a code sent to `nickname`, unless it is refused.
+    /// A refusal is the reason shown on the login page: too many codes were
+    /// requested, too many wrong codes entered, or a code is still pending.
+    pub fn request(
+        &self,
+        ip: Option<IpAddr>,
+        server: &str,
+        nickname: &str,
+    ) -> Result<(), &'static str> {
+        let key = nickname_key(server, nickname);
+        let ip = ip.map(|ip| format!("ip:{ip}"));
+        if !self.wrong_codes.allows(&key)
+            || !self.nickname_codes.allows(&key)
+            || ip.as_ref().is_some_and(|ip| !self.address_codes.allows(ip))
+        {
+            return Err("throttled");
+        }
+        // a client only ever has one code to guess
+        let now = chrono::Utc::now().timestamp();
+        if self
+            .pending
+            .lock()
+            .unwrap()
+            .values()
+            .any(|c| c.server == server && c.nickname == nickname && c.expires > now)
+        {
+            return Err("pending");
+        }
+
+        self.nickname_codes.record(&key);
+        if let Some(ip) = ip {
+            self.address_codes.record(&ip);
+        }
+        Ok(())
+    }
+
+    /// Remembers that `code` was sent to `client`, until it is entered in the
+    /// browser with the given `cookies`.
+    pub fn create(
+        &self,
+        cookies: &CookieJar<'_>,
+        server: &str,
+        client: i32,
+        nickname: &str,
+        code: String,
+    ) {
+        let token = format!("{:032x}", rand::random::<u128>());
+        let now = chrono::Utc::now().timestamp();
+
+        let mut challenges = self.pending.lock().unwrap();
+        challenges.retain(|_, challenge| challenge.expires > now);
+        challenges.insert(
+            token.clone(),
+            Challenge {
+                server: server.to_string(),
+                client,
+                nickname: nickname.to_string(),
+                code,
+                expires: now + CHALLENGE_SECONDS,
+                attempts: 0,
+            },
+        );
+        cookies.add_private(
+            Cookie::build((CHALLENGE_COOKIE, token))
+                .http_only(true)
+                .same_site(SameSite::Lax)
+                .max_age(rocket::time::Duration::seconds(CHALLENGE_SECONDS)),
+        );
+    }
+
+    /// Whether the browser has a challenge that is still valid.
+    pub fn is_pending(&self, cookies: &CookieJar<'_>) -> bool {
+        let Some(token) = cookies.get_private(CHALLENGE_COOKIE) else {
+            return false;
+        };
+        let now = chrono::Utc::now().timestamp();
+        self.pending
+            .lock()
+            .unwrap()
+            .get(token.value())
+            .is_some_and(|challenge| challenge.expires > now)
+    }
+
+    /// Returns the challenge of the browser if `code` matches it. The
+    /// challenge is consumed on success, after too many wrong codes, once it
+    /// expired and once its nickname is locked.
+    pub fn solve(&self, cookies: &CookieJar<'_>, code: &str) -> Option<Challenge> {
+        let token = cookies.get_private(CHALLENGE_COOKIE)?;
+        let token = token.value();
+        let mut challenges = self.pending.lock().unwrap();
+        let challenge = challenges.get_mut(token)?;
+        let key = nickname_key(&challenge.server, &challenge.nickname);
+
+        if challenge.expires <= chrono::Utc::now().timestamp() || !self.wrong_codes.allows(&key) {
+            challenges.remove(token);
+            cookies.remove_private(CHALLENGE_COOKIE);
+            return None;
+        }
+        if !constant_time_eq(challenge.code.as_bytes(), code.trim().as_bytes()) {
+            challenge.attempts += 1;
+            self.wrong_codes.record(&key);
+            if challenge.attempts >= CHALLENGE_ATTEMPTS {
+                challenges.remove(token);
+                cookies.remove_private(CHALLENGE_COOKIE);
+            }
+            return None;
+        }
+
+        cookies.remove_private(CHALLENGE_COOKIE);
+        challenges.remove(token)
+    }
+}
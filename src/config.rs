@@ -1,12 +1,16 @@
 use crate::errors::Error;
+use indexmap::IndexMap;
 use log::{debug, info};
 use rocket::figment::providers::{Env, Format, Serialized, Toml};
 use rocket::figment::Figment;
 use rocket::serde;
 use serde::{Deserialize, Serialize};
 use std::fs;
+use std::path::Path;
 
 use crate::augmentation::Augmentation;
+use crate::auth::{ApiTokenConfig, GroupRoles, UserConfig};
+use crate::permissions::{Permission, Roles};
 
 #[derive(Deserialize, Serialize, Default)]
 pub struct InternalConfig {
@@ -16,6 +20,36 @@ pub struct InternalConfig {
     pub afk_channel: Option<i32>,
 }
 
+/// Name of the connection configured by the top level options.
+pub const DEFAULT_CONNECTION: &str = "default";
+
+/// Connection to the query interface of a TeamSpeak host.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ConnectionConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub pass: String,
+    pub vsid: i32,
+    /// Virtual servers to manage, only `vsid` if empty.
+    #[serde(default)]
+    pub servers: Vec<i32>,
+    /// Server groups that may log in with their TeamSpeak identity.
+    #[serde(default)]
+    pub group_roles: GroupRoles,
+}
+
+impl ConnectionConfig {
+    /// Ids of all virtual servers to manage.
+    pub fn vsids(&self) -> Vec<i32> {
+        if self.servers.is_empty() {
+            vec![self.vsid]
+        } else {
+            self.servers.clone()
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct ExternalConfig {
     pub host: String,
@@ -23,8 +57,51 @@ pub struct ExternalConfig {
     pub user: String,
     pub pass: String,
     pub vsid: i32,
+    /// Virtual servers to manage, only `vsid` if empty.
+    #[serde(default)]
+    pub servers: Vec<i32>,
+    /// Server groups that may log in with their TeamSpeak identity.
+    #[serde(default)]
+    pub group_roles: GroupRoles,
+    /// Named connections to further hosts, replacing the top level one.
+    #[serde(default)]
+    pub connections: IndexMap<String, ConnectionConfig>,
     pub bind_addr: String,
     pub bind_port: u16,
+    /// Key used to encrypt session cookies, random on every start if unset.
+    #[serde(default)]
+    pub secret_key: Option<String>,
+    /// Local users of the web interface.
+    #[serde(default)]
+    pub users: IndexMap<String, UserConfig>,
+    /// Tokens for the JSON API, keyed by the name of their owner.
+    #[serde(default)]
+    pub api_tokens: IndexMap<String, ApiTokenConfig>,
+    /// Permissions of every role.
+    #[serde(default = "Roles::defaults")]
+    pub roles: IndexMap<String, Vec<Permission>>,
+}
+
+impl ExternalConfig {
+    /// All connections to manage, the top level one if no named connection
+    /// is configured.
+    pub fn connections(&self) -> IndexMap<String, ConnectionConfig> {
+        if !self.connections.is_empty() {
+            return self.connections.clone();
+        }
+        IndexMap::from([(
+            DEFAULT_CONNECTION.to_string(),
+            ConnectionConfig {
+                host: self.host.clone(),
+                port: self.port,
+                user: self.user.clone(),
+                pass: self.pass.clone(),
+                vsid: self.vsid,
+                servers: self.servers.clone(),
+                group_roles: self.group_roles.clone(),
+            },
+        )])
+    }
 }
 
 impl Default for ExternalConfig {
@@ -35,8 +112,15 @@ impl Default for ExternalConfig {
             user: "serveradmin".into(),
             pass: "password".into(),
             vsid: 1,
+            servers: Vec::new(),
+            group_roles: GroupRoles::default(),
+            connections: IndexMap::new(),
             bind_addr: "0.0.0.0".into(),
             bind_port: 8000,
+            secret_key: None,
+            users: IndexMap::new(),
+            api_tokens: IndexMap::new(),
+            roles: Roles::defaults(),
         }
     }
 }
@@ -45,17 +129,60 @@ impl Default for ExternalConfig {
 pub struct Config {
     pub internal: InternalConfig,
     pub external: ExternalConfig,
+    /// Name of the connection the internal state belongs to.
+    pub name: String,
+    /// Connection the internal state belongs to.
+    pub connection: ConnectionConfig,
+    /// Virtual server the internal state belongs to.
+    pub vsid: i32,
 }
 
 impl Config {
-    fn read_internal_config() -> Result<InternalConfig, Error> {
-        // check if state.toml exists
-        if let Ok(config_file) = fs::read_to_string("state.ron") {
-            return Ok(ron::from_str(&config_file)?);
+    /// Identifier of the virtual server `vsid` reached through the connection
+    /// `name`, used in routes and state file names.
+    pub fn server_id(name: &str, vsid: i32) -> String {
+        if name == DEFAULT_CONNECTION {
+            vsid.to_string()
+        } else {
+            format!("{name}-{vsid}")
+        }
+    }
+
+    /// State file of the virtual server `server`.
+    fn state_path(server: &str) -> String {
+        format!("state-{server}.ron")
+    }
+
+    fn read_internal_config(server: &str, legacy: bool) -> Result<InternalConfig, Error> {
+        let path = Config::state_path(server);
+        // earlier versions managed a single virtual server with a single state file
+        if legacy && !Path::new(&path).exists() && Path::new("state.ron").exists() {
+            info!("Moving state.ron to {path}");
+            fs::rename("state.ron", &path)?;
+        }
+
+        // check if the state file exists
+        if let Ok(config_file) = fs::read_to_string(&path) {
+            let mut internal: InternalConfig = ron::from_str(&config_file)?;
+            internal
+                .augmentations
+                .iter_mut()
+                .for_each(Augmentation::migrate);
+            // the planner relies on consistent options, which a hand-edited
+            // state file may lack
+            for augmentation in &internal.augmentations {
+                augmentation.validate().map_err(|e| {
+                    Error::Options(format!(
+                        "augmentation {} in {path}: {e}",
+                        augmentation.identifier
+                    ))
+                })?;
+            }
+            return Ok(internal);
         }
         // create empty config file
         fs::write(
-            "state.ron",
+            &path,
             ron::ser::to_string_pretty(
                 &InternalConfig {
                     augmentations: Vec::new(),
@@ -72,7 +199,7 @@ impl Config {
         })
     }
 
-    fn read_external_config() -> Result<ExternalConfig, Error> {
+    pub fn read_external_config() -> Result<ExternalConfig, Error> {
         Ok(
             Figment::from(Serialized::defaults(ExternalConfig::default()))
                 .merge(Toml::file("config.toml"))
@@ -82,36 +209,56 @@ impl Config {
                     "USER",
                     "PASS",
                     "VSID",
+                    "SERVERS",
                     "BIND_ADDR",
                     "BIND_PORT",
+                    "SECRET_KEY",
                 ]))
                 .extract::<ExternalConfig>()?,
         )
     }
 
-    pub fn read_config() -> Result<Config, Error> {
-        info!("Loading internal state from state.ron");
-        // try loading internal config
-        let internal = Config::read_internal_config()?;
-
+    /// Reads the configuration and the internal state of the virtual server
+    /// `vsid` reached through the connection `name`.
+    pub fn read_config(name: &str, vsid: i32) -> Result<Config, Error> {
         info!("Loading configuration from environment variables and config.toml");
         // load external config from env variables and config.toml
         // sensible defaults are provided
         let external = Config::read_external_config()?;
+        let connection = external
+            .connections()
+            .swap_remove(name)
+            .ok_or(Error::UnknownServer)?;
+
+        let server = Config::server_id(name, vsid);
+        info!(
+            "Loading internal state from {}",
+            Config::state_path(&server)
+        );
+        // try loading internal config
+        let legacy = name == DEFAULT_CONNECTION && vsid == connection.vsid;
+        let internal = Config::read_internal_config(&server, legacy)?;
 
         debug!(
-            "External config: Host: {}, Port: {}, User: {}, Pass: {}, BindAddr: {}, BindPort: {}",
-            external.host,
-            external.port,
-            external.user,
-            external.pass,
+            "External config: Connection: {}, Host: {}, Port: {}, User: {}, Pass: {}, BindAddr: {}, BindPort: {}",
+            name,
+            connection.host,
+            connection.port,
+            connection.user,
+            connection.pass,
             external.bind_addr,
             external.bind_port
         );
 
         info!("Successfully loaded configuration");
 
-        let config = Config { internal, external };
+        let config = Config {
+            internal,
+            external,
+            name: name.to_string(),
+            connection,
+            vsid,
+        };
         config.write_internal_config()?;
         Ok(config)
     }
@@ -122,12 +269,12 @@ impl Config {
         Ok(())
     }
 
-    pub fn remove_augmentation(&mut self, identifier: &str) -> Result<Augmentation, Error> {
+    pub fn remove_augmentation(&mut self, key: &str) -> Result<Augmentation, Error> {
         let index = self
             .internal
             .augmentations
             .iter()
-            .position(|c| c.identifier == identifier)
+            .position(|c| c.key() == key)
             .ok_or(Error::NotFound)?;
         let augmentation = self.internal.augmentations.remove(index);
         self.write_internal_config()?;
@@ -136,7 +283,10 @@ impl Config {
 
     pub fn write_internal_config(&self) -> Result<(), Error> {
         let data = ron::ser::to_string_pretty(&self.internal, ron::ser::PrettyConfig::default())?;
-        fs::write("state.ron", data)?;
+        fs::write(
+            Config::state_path(&Config::server_id(&self.name, self.vsid)),
+            data,
+        )?;
         Ok(())
     }
 }
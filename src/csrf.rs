@@ -0,0 +1,61 @@
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+
+use crate::auth::constant_time_eq;
+
+/// Name of the private cookie holding the token.
+const CSRF_COOKIE: &str = "csrf";
+/// Header the frontend sends the token in.
+const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Token protecting mutations against cross-site requests. Pages embed it,
+/// the frontend sends it back in the `X-CSRF-Token` header and plain forms in
+/// a `csrf` field. Other sites can neither read the page nor the encrypted
+/// cookie, so they cannot forge it.
+pub struct Csrf;
+
+impl Csrf {
+    /// Token of the browser, created on first use.
+    pub fn token(cookies: &CookieJar<'_>) -> String {
+        if let Some(cookie) = cookies.get_private(CSRF_COOKIE) {
+            return cookie.value().to_string();
+        }
+        let token = format!("{:032x}", rand::random::<u128>());
+        cookies.add_private(
+            Cookie::build((CSRF_COOKIE, token.clone()))
+                .http_only(true)
+                .same_site(SameSite::Strict),
+        );
+        token
+    }
+
+    /// Whether `token` is the token of the browser.
+    pub fn verify(cookies: &CookieJar<'_>, token: &str) -> bool {
+        let Some(cookie) = cookies.get_private(CSRF_COOKIE) else {
+            return false;
+        };
+        constant_time_eq(cookie.value().as_bytes(), token.as_bytes())
+    }
+}
+
+/// Request guard for mutations, which fails with 403 unless the request
+/// carries the CSRF token of the browser in its header.
+pub struct CsrfChecked;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfChecked {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.headers().get_one(CSRF_HEADER) {
+            Some(token) if Csrf::verify(req.cookies(), token) => Outcome::Success(CsrfChecked),
+            _ => {
+                req.local_cache(|| GuardError("Invalid CSRF token, please reload the page"));
+                Outcome::Error((Status::Forbidden, ()))
+            }
+        }
+    }
+}
+
+/// Reason a request guard failed, shown by the error catchers.
+pub struct GuardError(pub &'static str);
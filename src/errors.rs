@@ -1,10 +1,38 @@
+use rocket::http::Status;
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use rocket::Request;
+use serde::Serialize;
 use thiserror::Error;
 use ts3_query_api::error::QueryError;
+use utoipa::ToSchema;
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Augmentation not found")]
     NotFound,
+    #[error("Invalid augmentation name")]
+    InvalidName,
+    #[error("Channel is augmented already")]
+    AlreadyAugmented,
+    #[error("Virtual server not found")]
+    UnknownServer,
+    #[error("No single client with this nickname is connected")]
+    UnknownClient,
+    #[error("Channel not found")]
+    UnknownChannel,
+    #[error("Client not found")]
+    UnknownClientId,
+    #[error("Invalid numbering scheme: {0}")]
+    Numbering(String),
+    #[error("Invalid name template: {0}")]
+    Template(String),
+    #[error("Invalid augmentation options: {0}")]
+    Options(String),
+    #[error("Virtual server is managed by another Sunbird")]
+    Leased,
+    #[error("Not connected to the TeamSpeak server query")]
+    Disconnected,
     #[error("Regex Error: {0}")]
     Regex(#[from] regex::Error),
     #[error("Query Error: {0}")]
@@ -22,3 +50,56 @@ pub enum Error {
     #[error("Badge parse error: {0}")]
     BadgeParse(#[from] crate::badges::ParseError),
 }
+
+impl Error {
+    /// HTTP status that describes the error best.
+    pub fn status(&self) -> Status {
+        match self {
+            Error::NotFound
+            | Error::UnknownServer
+            | Error::UnknownClient
+            | Error::UnknownChannel
+            | Error::UnknownClientId => Status::NotFound,
+            Error::AlreadyAugmented | Error::Leased => Status::Conflict,
+            Error::InvalidName | Error::Numbering(_) | Error::Template(_) | Error::Options(_) => {
+                Status::BadRequest
+            }
+            // the TeamSpeak server or the badge list are upstream of Sunbird
+            Error::Disconnected | Error::Query(_) | Error::Reqwest(_) => Status::BadGateway,
+            Error::Regex(_)
+            | Error::State1(_)
+            | Error::State2(_)
+            | Error::Parse(_)
+            | Error::IO(_)
+            | Error::BadgeParse(_) => Status::InternalServerError,
+        }
+    }
+}
+
+/// Body of every failed API request.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// Always `false`.
+    pub ok: bool,
+    pub error: String,
+}
+
+impl ErrorBody {
+    pub fn new(error: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            error: error.into(),
+        }
+    }
+}
+
+/// Answers API requests with `{"ok": false, "error": "<message>"}`.
+impl<'r> Responder<'r, 'static> for Error {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        let body = Json(ErrorBody::new(self.to_string()));
+        response::Response::build_from(body.respond_to(req)?)
+            .status(status)
+            .ok()
+    }
+}
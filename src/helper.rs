@@ -1,15 +1,17 @@
 use base64::{engine::general_purpose, Engine as _};
 use chrono::Duration;
 use lazy_static::lazy_static;
+use log::error;
 use regex::Regex;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{cell::Cell, collections::HashMap, fs};
 use ts3_query_api::definitions::{ChannelListDynamicEntry, ClientListDynamicEntry};
+use utoipa::ToSchema;
 
 use crate::{badges::BadgesFile, errors::Error};
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, PartialEq, ToSchema)]
 pub struct Channel {
     pub id: i32,
     pub name: String,
@@ -18,10 +20,11 @@ pub struct Channel {
     pub is_augmented: bool,
     pub augmentation_id: Option<String>,
     pub highlight_color: Option<String>,
+    #[schema(value_type = i32)]
     pub indent_level: Cell<i32>,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, PartialEq, ToSchema)]
 pub struct Client {
     pub id: i32,
     pub name: String,
@@ -149,3 +152,39 @@ pub fn format_duration(seconds: i64) -> String {
 
     result
 }
+
+/// Addresses a virtual server listens on, from its comma separated IPs.
+pub fn listen_addresses(ip: &str, port: impl std::fmt::Display) -> Vec<String> {
+    ip.split(", ")
+        .map(|ip| {
+            if ip.contains(':') {
+                format!("[{ip}]:{port}")
+            } else {
+                format!("{ip}:{port}")
+            }
+        })
+        .collect()
+}
+
+/// JSON meta data the TeamSpeak client stores about itself.
+#[derive(Default, Deserialize)]
+pub struct ClientMetaData {
+    _myts_token: Option<String>,
+    pub tag: Option<String>,
+    _updated: Option<u64>,
+}
+
+impl ClientMetaData {
+    pub fn parse(meta_data: Option<&str>) -> Self {
+        match meta_data {
+            Some(meta_data) => match serde_json::from_str::<ClientMetaData>(meta_data) {
+                Ok(meta_data) => meta_data,
+                Err(e) => {
+                    error!("Could not parse meta data: {e}");
+                    ClientMetaData::default()
+                }
+            },
+            None => ClientMetaData::default(),
+        }
+    }
+}
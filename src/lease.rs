@@ -0,0 +1,94 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+/// Time a lease stays valid without being renewed.
+pub const LEASE_DURATION: Duration = Duration::from_secs(90);
+/// Interval in which the holder renews its lease.
+pub const LEASE_RENEWAL: Duration = Duration::from_secs(30);
+
+const PREFIX: &str = "sunbird-lease";
+
+/// Lease on a virtual server, stored in the description of the query client
+/// holding it, so that only one Sunbird manages a server at a time.
+pub struct Lease {
+    /// Random identifier of the Sunbird holding the lease.
+    pub owner: String,
+    /// Unix timestamp after which the lease may be taken over.
+    pub expires: u64,
+}
+
+impl Lease {
+    /// Lease for `owner` that is valid for [`LEASE_DURATION`].
+    pub fn new(owner: &str) -> Self {
+        Self {
+            owner: owner.to_string(),
+            expires: now() + LEASE_DURATION.as_secs(),
+        }
+    }
+
+    /// Parses a lease from a client description, `None` if there is none.
+    pub fn parse(description: &str) -> Option<Self> {
+        let mut parts = description.trim().splitn(3, ':');
+        if parts.next()? != PREFIX {
+            return None;
+        }
+        Some(Self {
+            owner: parts.next()?.to_string(),
+            expires: parts.next()?.parse().ok()?,
+        })
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires <= now()
+    }
+}
+
+impl Display for Lease {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{PREFIX}:{}:{}", self.owner, self.expires)
+    }
+}
+
+fn now() -> u64 {
+    chrono::Utc::now().timestamp() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lease_round_trips() {
+        let lease = Lease::new("a1b2c3");
+        let parsed = Lease::parse(&lease.to_string()).unwrap();
+        assert_eq!(parsed.owner, "a1b2c3");
+        assert_eq!(parsed.expires, lease.expires);
+        assert!(!parsed.is_expired());
+    }
+
+    #[test]
+    fn lease_is_parsed() {
+        let lease = Lease::parse(" sunbird-lease:a1b2c3:1700000000\n").unwrap();
+        assert_eq!(lease.owner, "a1b2c3");
+        assert_eq!(lease.expires, 1_700_000_000);
+        assert!(lease.is_expired());
+    }
+
+    #[test]
+    fn other_descriptions_are_no_lease() {
+        for description in [
+            "",
+            "Music bot",
+            "sunbird-lease",
+            "sunbird-lease:a1b2c3",
+            "sunbird-lease:a1b2c3:soon",
+            "sunbird-lease:a1b2c3:-1",
+            "other-lease:a1b2c3:1700000000",
+        ] {
+            assert!(
+                Lease::parse(description).is_none(),
+                "{description} is parsed"
+            );
+        }
+    }
+}
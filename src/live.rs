@@ -0,0 +1,430 @@
+use log::error;
+use rocket::response::stream::{Event, EventStream};
+use rocket::{get, Shutdown, State};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::time::{interval, sleep};
+use utoipa::ToSchema;
+
+use crate::augmentation::AugmentationClient;
+use crate::errors::{Error, ErrorBody};
+use crate::helper::{Channel, Client};
+use crate::permissions::CanViewTree;
+use crate::servers::Servers;
+use crate::tree::Tree;
+
+/// Time without further events after which the tree is diffed.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+/// Interval of the diffs catching up on changes the query sends no event
+/// for, like the talk power of clients.
+const REFRESH: Duration = Duration::from_secs(5);
+/// Batches buffered for a slow browser before it is reset instead.
+const BUFFER: usize = 64;
+
+/// Change of the tree, as pushed to the browsers showing it.
+#[derive(Serialize, ToSchema)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum TreeChange {
+    /// The whole tree is redrawn, sent on connect and when channels moved.
+    Reset {
+        tree: Tree,
+    },
+    ServerRenamed {
+        name: String,
+    },
+    /// Created channel, placed right after the channel `after` and its
+    /// clients, or at the top if `None`.
+    ChannelCreated {
+        channel: Channel,
+        after: Option<i32>,
+    },
+    /// Renamed channel, or one whose talk power or augmentation changed.
+    ChannelUpdated {
+        channel: Channel,
+    },
+    ChannelDeleted {
+        id: i32,
+    },
+    /// Client that connected, `index` is its position in its channel.
+    ClientJoined {
+        client: Client,
+        index: usize,
+    },
+    ClientMoved {
+        client: Client,
+        index: usize,
+    },
+    /// Client whose talk status, nickname or badges changed.
+    ClientUpdated {
+        client: Client,
+        index: usize,
+    },
+    ClientLeft {
+        id: i32,
+    },
+}
+
+/// Changes turning the `old` tree into the `new` one. Channels come before
+/// clients and both are ordered like the tree, so positions only refer to
+/// items that are already in place.
+fn diff(old: &Tree, new: &Tree) -> Vec<TreeChange> {
+    let kept = |from: &Tree, to: &Tree| {
+        from.channel_order
+            .iter()
+            .filter(|id| to.channel_map.contains_key(id))
+            .copied()
+            .collect::<Vec<_>>()
+    };
+    let kept_old = kept(old, new);
+    let kept_new = kept(new, old);
+    // a moved channel drags its sub-channels and clients along, which is
+    // simpler to redraw than to describe
+    if kept_old != kept_new
+        || kept_new
+            .iter()
+            .any(|id| old.channel_map[id].parent_id != new.channel_map[id].parent_id)
+    {
+        return vec![TreeChange::Reset { tree: new.clone() }];
+    }
+
+    let mut changes = Vec::new();
+    if old.server_name != new.server_name {
+        changes.push(TreeChange::ServerRenamed {
+            name: new.server_name.clone(),
+        });
+    }
+
+    for id in &old.channel_order {
+        if !new.channel_map.contains_key(id) {
+            changes.push(TreeChange::ChannelDeleted { id: *id });
+        }
+    }
+    let mut after = None;
+    for id in &new.channel_order {
+        let channel = &new.channel_map[id];
+        match old.channel_map.get(id) {
+            None => changes.push(TreeChange::ChannelCreated {
+                channel: channel.clone(),
+                after,
+            }),
+            Some(previous) if previous != channel => changes.push(TreeChange::ChannelUpdated {
+                channel: channel.clone(),
+            }),
+            Some(_) => {}
+        }
+        after = Some(*id);
+    }
+
+    let clients = |tree: &Tree| {
+        tree.channel_order
+            .iter()
+            .filter_map(|id| tree.clients.get(id))
+            .flatten()
+            .map(|client| (client.id, client.clone()))
+            .collect::<Vec<_>>()
+    };
+    let connected = clients(new)
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect::<HashSet<_>>();
+    let old_clients = clients(old);
+    for (id, _) in &old_clients {
+        if !connected.contains(id) {
+            changes.push(TreeChange::ClientLeft { id: *id });
+        }
+    }
+    let old_clients = old_clients.into_iter().collect::<HashMap<_, _>>();
+    for id in &new.channel_order {
+        for (index, client) in new.clients.get(id).into_iter().flatten().enumerate() {
+            let client = client.clone();
+            match old_clients.get(&client.id) {
+                None => changes.push(TreeChange::ClientJoined { client, index }),
+                Some(previous) if previous.channel != client.channel => {
+                    changes.push(TreeChange::ClientMoved { client, index })
+                }
+                Some(previous) if *previous != client => {
+                    changes.push(TreeChange::ClientUpdated { client, index })
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    changes
+}
+
+/// Diffs the tree of a virtual server whenever its events report a change
+/// and pushes the changes to the browsers showing it.
+#[derive(Clone)]
+pub struct LiveTree {
+    requests: UnboundedSender<()>,
+    /// Batches of changes, serialized once for all browsers.
+    changes: broadcast::Sender<Arc<str>>,
+}
+
+impl LiveTree {
+    pub fn spawn(client: Arc<AugmentationClient>) -> Self {
+        let (requests, mut receiver) = mpsc::unbounded_channel();
+        let (changes, _) = broadcast::channel(BUFFER);
+
+        let sender = changes.clone();
+        tokio::spawn(async move {
+            let mut last: Option<Tree> = None;
+            let mut refresh = interval(REFRESH);
+            loop {
+                tokio::select! {
+                    request = receiver.recv() => {
+                        if request.is_none() {
+                            break;
+                        }
+                        // wait until the burst settles
+                        loop {
+                            tokio::select! {
+                                request = receiver.recv() => if request.is_none() { break },
+                                _ = sleep(DEBOUNCE) => break,
+                            }
+                        }
+                    }
+                    _ = refresh.tick() => {}
+                }
+
+                // nobody is watching, whoever connects next gets a reset anyway
+                if sender.receiver_count() == 0 {
+                    last = None;
+                    continue;
+                }
+                let tree = match client.tree().await {
+                    Ok(tree) => tree,
+                    Err(e) => {
+                        error!("Could not build tree: {e}");
+                        continue;
+                    }
+                };
+                let batch = match &last {
+                    Some(last) => diff(last, &tree),
+                    // browsers that connected in the meantime may have missed changes
+                    None => vec![TreeChange::Reset { tree: tree.clone() }],
+                };
+                if !batch.is_empty() {
+                    match serde_json::to_string(&batch) {
+                        Ok(batch) => {
+                            let _ = sender.send(batch.into());
+                        }
+                        Err(e) => error!("Could not serialize tree changes: {e}"),
+                    }
+                }
+                last = Some(tree);
+            }
+        });
+
+        Self { requests, changes }
+    }
+
+    /// Requests a diff, the query reported a change of the tree.
+    pub fn request(&self) {
+        let _ = self.requests.send(());
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Arc<str>> {
+        let receiver = self.changes.subscribe();
+        self.request();
+        receiver
+    }
+}
+
+/// Live trees of all virtual servers, keyed like [`Servers`].
+#[derive(Default)]
+pub struct LiveTrees(HashMap<String, LiveTree>);
+
+impl LiveTrees {
+    pub fn insert(&mut self, server: String, live: LiveTree) {
+        self.0.insert(server, live);
+    }
+
+    pub fn get(&self, server: &str) -> Option<&LiveTree> {
+        self.0.get(server)
+    }
+}
+
+/// Stream of changes of the tree, starting with a reset to the current tree.
+#[utoipa::path(
+    get,
+    path = "/s/{server}/events",
+    params(
+        ("server" = String, Path, description = "Id of the virtual server"),
+    ),
+    responses(
+        (status = 200, description = "Server-Sent Events, each carrying a batch of changes as JSON array", content_type = "text/event-stream", body = [TreeChange]),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Unknown virtual server", body = ErrorBody),
+    ),
+    security((), ("session" = []))
+)]
+#[get("/s/<server>/events")]
+pub async fn events(
+    _access: CanViewTree,
+    servers: &State<Servers>,
+    live: &State<LiveTrees>,
+    server: &str,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], Error> {
+    let client = servers.get(server).ok_or(Error::UnknownServer)?;
+    let mut changes = live.get(server).ok_or(Error::UnknownServer)?.subscribe();
+
+    Ok(EventStream! {
+        let mut reset = true;
+        loop {
+            if reset {
+                match client.tree().await {
+                    Ok(tree) => yield Event::json(&[TreeChange::Reset { tree }]),
+                    Err(e) => error!("Could not build tree: {e}"),
+                }
+                reset = false;
+            }
+            let batch = tokio::select! {
+                batch = changes.recv() => batch,
+                _ = &mut shutdown => break,
+            };
+            match batch {
+                Ok(batch) => yield Event::data(batch.to_string()),
+                // the browser fell behind, start over
+                Err(RecvError::Lagged(_)) => reset = true,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Tree of `channels` as (id, parent) and `clients` as (id, channel).
+    fn tree(channels: &[(i32, i32)], clients: &[(i32, i32)]) -> Tree {
+        let mut tree = Tree {
+            server_name: "Server".to_string(),
+            channel_order: channels.iter().map(|&(id, _)| id).collect(),
+            channel_map: HashMap::new(),
+            clients: HashMap::new(),
+        };
+        for &(id, parent_id) in channels {
+            let channel = Channel {
+                id,
+                name: format!("Channel {id}"),
+                parent_id,
+                talk_power: 0,
+                is_augmented: false,
+                augmentation_id: None,
+                highlight_color: None,
+                indent_level: Cell::new(0),
+            };
+            tree.channel_map.insert(id, channel);
+        }
+        for &(id, channel) in clients {
+            let client = Client {
+                id,
+                name: format!("Client {id}"),
+                channel,
+                is_query: false,
+                talk_power: 0,
+                can_talk: true,
+                badges: Vec::new(),
+                country: None,
+            };
+            tree.clients.entry(channel).or_default().push(client);
+        }
+        tree
+    }
+
+    fn describe(changes: Vec<TreeChange>) -> Vec<String> {
+        changes
+            .iter()
+            .map(|change| match change {
+                TreeChange::Reset { .. } => "reset".to_string(),
+                TreeChange::ServerRenamed { name } => format!("server renamed to {name}"),
+                TreeChange::ChannelCreated { channel, after } => {
+                    format!("channel {} created after {after:?}", channel.id)
+                }
+                TreeChange::ChannelUpdated { channel } => format!("channel {} updated", channel.id),
+                TreeChange::ChannelDeleted { id } => format!("channel {id} deleted"),
+                TreeChange::ClientJoined { client, index } => {
+                    format!(
+                        "client {} joined channel {} at {index}",
+                        client.id, client.channel
+                    )
+                }
+                TreeChange::ClientMoved { client, index } => {
+                    format!(
+                        "client {} moved to channel {} at {index}",
+                        client.id, client.channel
+                    )
+                }
+                TreeChange::ClientUpdated { client, index } => {
+                    format!(
+                        "client {} updated in channel {} at {index}",
+                        client.id, client.channel
+                    )
+                }
+                TreeChange::ClientLeft { id } => format!("client {id} left"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unchanged_tree_has_no_changes() {
+        let old = tree(&[(1, 0), (2, 1)], &[(10, 1)]);
+        let new = tree(&[(1, 0), (2, 1)], &[(10, 1)]);
+        assert!(diff(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn channel_changes_are_diffed() {
+        let old = tree(&[(1, 0), (2, 0), (3, 0)], &[]);
+        let mut new = tree(&[(1, 0), (4, 1), (2, 0)], &[]);
+        new.server_name = "Renamed".to_string();
+        new.channel_map.get_mut(&2).unwrap().name = "Renamed".to_string();
+        assert_eq!(
+            describe(diff(&old, &new)),
+            [
+                "server renamed to Renamed",
+                "channel 3 deleted",
+                "channel 4 created after Some(1)",
+                "channel 2 updated",
+            ]
+        );
+
+        let new = tree(&[(5, 0), (1, 0), (2, 0), (3, 0)], &[]);
+        assert_eq!(describe(diff(&old, &new)), ["channel 5 created after None"]);
+    }
+
+    #[test]
+    fn moved_channels_reset_the_tree() {
+        let old = tree(&[(1, 0), (2, 0), (3, 0)], &[]);
+        let new = tree(&[(1, 0), (3, 0), (2, 0)], &[]);
+        assert_eq!(describe(diff(&old, &new)), ["reset"]);
+        let new = tree(&[(1, 0), (2, 1), (3, 0)], &[]);
+        assert_eq!(describe(diff(&old, &new)), ["reset"]);
+    }
+
+    #[test]
+    fn client_changes_are_diffed() {
+        let old = tree(&[(1, 0), (2, 0)], &[(10, 1), (11, 1), (12, 2)]);
+        let mut new = tree(&[(1, 0), (2, 0)], &[(11, 1), (12, 1), (13, 2)]);
+        new.clients.get_mut(&1).unwrap()[0].can_talk = false;
+        assert_eq!(
+            describe(diff(&old, &new)),
+            [
+                "client 10 left",
+                "client 11 updated in channel 1 at 0",
+                "client 12 moved to channel 1 at 1",
+                "client 13 joined channel 2 at 0",
+            ]
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use base64::{engine::general_purpose, Engine as _};
 use env_logger::{fmt::Color, Builder, Env};
 use helper::extract_spacer_name;
 use lazy_static::lazy_static;
@@ -9,22 +10,49 @@ use rocket_dyn_templates::Template;
 use std::io::Write;
 use std::sync::Arc;
 use ts3_query_api::event::Event;
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
 
+mod api;
 mod augmentation;
+mod auth;
 mod badges;
 mod config;
+mod csrf;
 mod errors;
 mod helper;
+mod lease;
+mod live;
+mod numbering;
+mod openapi;
+mod permissions;
+mod planner;
+mod queue;
 mod requests;
 mod rocket_errors;
+mod servers;
+mod template;
 mod tree;
 
-use augmentation::AugmentationClient;
+use augmentation::{AugmentationClient, ConnectionState};
+use auth::{hash_password, ApiTokens, Challenges, Users};
+use config::Config;
+use errors::Error;
+use lease::LEASE_RENEWAL;
+use live::{LiveTree, LiveTrees};
+use openapi::ApiDoc;
+use permissions::Roles;
+use queue::ReconciliationQueue;
 use requests::{
-    abridge, assets, augment, augmentation as augmentation_route, badge, change_prefix, channel,
-    client, favicon, tree as tree_route,
+    abridge, adopt, assets, augment, augmentation as augmentation_route, badge, change_prefix,
+    channel, client, connection, favicon, foreign, foreign_groups, login, login_code, login_page,
+    logout, plan, resync, servers as servers_route, teamspeak_login, tree as tree_route,
 };
-use rocket_errors::{internal_error, not_found};
+use rocket_errors::{
+    api_not_found, api_unauthorized, bad_gateway, forbidden, internal_error, not_found,
+    unauthorized, unprocessable,
+};
+use servers::Servers;
 
 use crate::helper::base64_encode;
 
@@ -66,44 +94,86 @@ async fn main() {
         })
         .init();
 
+    // print a hash for the users section of the config instead of starting
+    if std::env::args().any(|arg| arg == "--hash-password") {
+        let mut password = String::new();
+        if let Err(e) = std::io::stdin().read_line(&mut password) {
+            error!("Could not read password: {e}");
+            return;
+        }
+        println!("{}", hash_password(password.trim_end_matches(['\r', '\n'])));
+        return;
+    }
+
     info!("Starting up");
 
-    let client = match AugmentationClient::new().await {
-        Ok(client) => client,
+    let dry_run = std::env::args().any(|arg| arg == "--dry-run");
+
+    let external = match Config::read_external_config() {
+        Ok(external) => external,
         Err(e) => {
-            error!("Could not connect to server: {e}");
+            error!("Could not load configuration: {e}");
             return;
         }
     };
-    let event_client = Arc::new(client);
-    let managed_client = event_client.clone();
 
-    info!("Successfully connected to TeamSpeak server query");
+    // hosts are connected concurrently, so an unreachable one does not hold up the others
+    let mut connecting = Vec::new();
+    for (name, connection) in external.connections() {
+        for vsid in connection.vsids() {
+            let name = name.clone();
+            connecting.push(tokio::spawn(async move {
+                let client = AugmentationClient::new(dry_run, &name, vsid).await;
+                (Config::server_id(&name, vsid), client)
+            }));
+        }
+    }
 
-    tokio::spawn(async move {
-        while let Ok(event) = event_client.client.wait_for_event().await {
-            match event {
-                Event::ClientMoved(_) | Event::ClientEnterView(_) | Event::ClientLeftView(_) => {
-                    match event_client.update_augmented_channels().await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!("Could not update augmented channels: {e}");
-                        }
-                    }
-                }
-                _ => {}
+    let mut servers = Servers::default();
+    let mut live_trees = LiveTrees::default();
+    for handle in connecting {
+        let Ok((server, client)) = handle.await else {
+            continue;
+        };
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Could not set up virtual server {server}: {e}");
+                continue;
+            }
+        };
+
+        if dry_run {
+            if let Err(e) = client.log_plans().await {
+                error!("Could not plan augmentations of virtual server {server}: {e}");
             }
+            continue;
         }
-    });
 
-    let addr = managed_client
-        .config
-        .lock()
-        .await
-        .external
-        .bind_addr
-        .clone();
-    let port = managed_client.config.lock().await.external.bind_port;
+        let client = Arc::new(client);
+        live_trees.insert(server.clone(), supervise(client.clone()));
+        servers.insert(server, client);
+    }
+    if dry_run {
+        return;
+    }
+    if servers.is_empty() {
+        error!("Could not set up any virtual server");
+        return;
+    }
+
+    let addr = external.bind_addr;
+    let port = external.bind_port;
+    // sessions do not survive a restart without a configured key
+    let secret_key = external
+        .secret_key
+        .unwrap_or_else(|| general_purpose::STANDARD.encode(rand::random::<[u8; 32]>()));
+    let users = Users::new(external.users);
+    let roles = Roles::new(external.roles);
+    let api_tokens = ApiTokens::new(external.api_tokens);
+    if users.is_empty() {
+        info!("No local users configured, only TeamSpeak identities can log in");
+    }
 
     info!("Starting web server on {addr}:{port}");
 
@@ -112,9 +182,15 @@ async fn main() {
         .configure(
             rocket::Config::figment()
                 .merge(("port", port))
-                .merge(("address", &addr)),
+                .merge(("address", &addr))
+                .merge(("secret_key", secret_key)),
         )
-        .manage(managed_client)
+        .manage(servers)
+        .manage(live_trees)
+        .manage(users)
+        .manage(Challenges::default())
+        .manage(roles)
+        .manage(api_tokens)
         .attach(Template::custom(|engines| {
             // Add your custom filter to the Tera instance
             engines
@@ -130,14 +206,115 @@ async fn main() {
                 augment,
                 abridge,
                 tree_route,
+                servers_route,
                 favicon,
                 badge,
                 channel,
                 client,
-                change_prefix
+                change_prefix,
+                plan,
+                resync,
+                foreign,
+                foreign_groups,
+                adopt,
+                connection,
+                login_page,
+                login,
+                teamspeak_login,
+                login_code,
+                logout,
+                live::events
             ],
         )
-        .register("/", catchers![internal_error, not_found])
+        .mount(
+            "/api/v1",
+            routes![
+                api::server,
+                api::tree,
+                api::channel,
+                api::client,
+                api::augmentations
+            ],
+        )
+        .register(
+            "/",
+            catchers![
+                internal_error,
+                bad_gateway,
+                not_found,
+                unauthorized,
+                forbidden,
+                unprocessable
+            ],
+        )
+        .mount(
+            "/",
+            SwaggerUi::new("/api/explorer/<_..>").url("/api/openapi.json", ApiDoc::openapi()),
+        )
+        .register("/api", catchers![api_unauthorized, api_not_found])
         .launch()
         .await;
 }
+
+/// Reconciles the augmentations of a virtual server in reaction to its events
+/// and keeps its lease and connection alive. Returns the live tree fed by the
+/// same events.
+fn supervise(client: Arc<AugmentationClient>) -> LiveTree {
+    let queue = ReconciliationQueue::spawn(client.clone());
+    let live = LiveTree::spawn(client.clone());
+
+    let lease_client = client.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(LEASE_RENEWAL);
+        loop {
+            interval.tick().await;
+            // the lease is renewed by the reconnect itself
+            if !matches!(lease_client.connection(), ConnectionState::Connected) {
+                continue;
+            }
+            if let Err(e) = lease_client.renew_lease().await {
+                error!("Could not renew lease: {e}");
+            }
+        }
+    });
+
+    let event_live = live.clone();
+    tokio::spawn(async move {
+        loop {
+            // servers unreachable at startup are connected by the reconnect
+            let event = match client.client() {
+                Ok(query) => query.wait_for_event().await.map_err(Error::from),
+                Err(e) => Err(e),
+            };
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    error!("Lost connection to TeamSpeak server query: {e}");
+                    client.reconnect().await;
+                    // catch up on everything that happened while disconnected
+                    queue.request_sync();
+                    event_live.request();
+                    continue;
+                }
+            };
+            match event {
+                Event::ChannelEdited(_) => {
+                    queue.request_sync();
+                    event_live.request();
+                }
+                Event::ClientMoved(_)
+                | Event::ClientEnterView(_)
+                | Event::ClientLeftView(_)
+                | Event::ChannelCreated(_)
+                | Event::ChannelDeleted(_)
+                | Event::ChannelMoved(_) => {
+                    queue.request();
+                    event_live.request();
+                }
+                _ => {}
+            }
+        }
+    });
+
+    live
+}
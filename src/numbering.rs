@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::errors::Error;
+
+/// Widest padding of [`NumberingScheme::ZeroPadded`], more does not fit in a
+/// channel name anyway.
+const MAX_WIDTH: usize = 10;
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum NumberingScheme {
+    #[default]
+    Roman,
+    Arabic,
+    ZeroPadded(usize),
+    Letters,
+    Words(Vec<String>),
+}
+
+impl NumberingScheme {
+    /// Formats the 1-based `index`, or `None` if the scheme cannot represent it.
+    pub fn format(&self, index: usize) -> Option<String> {
+        if index == 0 {
+            return None;
+        }
+        match self {
+            NumberingScheme::Roman => roman::to(i32::try_from(index).ok()?),
+            NumberingScheme::Arabic => Some(index.to_string()),
+            NumberingScheme::ZeroPadded(width) => Some(format!("{index:0width$}")),
+            NumberingScheme::Letters => {
+                // bijective base 26: A..Z, AA..AZ, BA..
+                let mut letters = Vec::new();
+                let mut n = index;
+                while n > 0 {
+                    n -= 1;
+                    letters.push((b'A' + (n % 26) as u8) as char);
+                    n /= 26;
+                }
+                Some(letters.into_iter().rev().collect())
+            }
+            NumberingScheme::Words(words) => words.get(index - 1).cloned(),
+        }
+    }
+
+    /// Regex fragment matching every numeral produced by `format`.
+    pub fn pattern(&self) -> String {
+        match self {
+            NumberingScheme::Roman => "[IVXLCDM]+".to_string(),
+            NumberingScheme::Arabic => "[1-9][0-9]*".to_string(),
+            NumberingScheme::ZeroPadded(width) => format!("[0-9]{{{width},}}"),
+            NumberingScheme::Letters => "[A-Z]+".to_string(),
+            NumberingScheme::Words(words) => format!(
+                "(?:{})",
+                words
+                    .iter()
+                    .map(|w| regex::escape(w))
+                    .collect::<Vec<_>>()
+                    .join("|")
+            ),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if let NumberingScheme::ZeroPadded(width) = self {
+            // a width of 0 would match the bare identifier as instance
+            if !(1..=MAX_WIDTH).contains(width) {
+                return Err(Error::Numbering(format!(
+                    "padding width must be between 1 and {MAX_WIDTH}"
+                )));
+            }
+        }
+        if let NumberingScheme::Words(words) = self {
+            if words.is_empty() {
+                return Err(Error::Numbering("word list is empty".to_string()));
+            }
+            if words.iter().any(|w| w.trim().is_empty()) {
+                return Err(Error::Numbering(
+                    "word list contains empty words".to_string(),
+                ));
+            }
+            for (i, word) in words.iter().enumerate() {
+                if words[..i].contains(word) {
+                    return Err(Error::Numbering(format!("word {word} is used twice")));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Short name used by the augmentation form.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NumberingScheme::Roman => "roman",
+            NumberingScheme::Arabic => "arabic",
+            NumberingScheme::ZeroPadded(_) => "zero_padded",
+            NumberingScheme::Letters => "letters",
+            NumberingScheme::Words(_) => "words",
+        }
+    }
+
+    /// Scheme specific argument as shown in the augmentation form.
+    pub fn argument(&self) -> String {
+        match self {
+            NumberingScheme::ZeroPadded(width) => width.to_string(),
+            NumberingScheme::Words(words) => words.join(", "),
+            _ => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn words() -> NumberingScheme {
+        NumberingScheme::Words(vec!["Alpha".into(), "Beta (2)".into(), "C.".into()])
+    }
+
+    #[test]
+    fn numerals_are_formatted() {
+        let format = |scheme: &NumberingScheme, indices: &[usize]| {
+            indices
+                .iter()
+                .map(|&i| scheme.format(i).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            format(&NumberingScheme::Roman, &[1, 4, 9, 14, 40, 1994]),
+            ["I", "IV", "IX", "XIV", "XL", "MCMXCIV"]
+        );
+        assert_eq!(format(&NumberingScheme::Arabic, &[1, 10]), ["1", "10"]);
+        assert_eq!(
+            format(&NumberingScheme::ZeroPadded(3), &[7, 42, 1234]),
+            ["007", "042", "1234"]
+        );
+        assert_eq!(
+            format(&NumberingScheme::Letters, &[1, 26, 27, 52, 53, 702, 703]),
+            ["A", "Z", "AA", "AZ", "BA", "ZZ", "AAA"]
+        );
+        assert_eq!(format(&words(), &[1, 3]), ["Alpha", "C."]);
+    }
+
+    #[test]
+    fn unrepresentable_numerals_are_none() {
+        for scheme in [
+            NumberingScheme::Roman,
+            NumberingScheme::Arabic,
+            NumberingScheme::ZeroPadded(2),
+            NumberingScheme::Letters,
+            words(),
+        ] {
+            assert_eq!(scheme.format(0), None);
+        }
+        assert_eq!(words().format(4), None);
+    }
+
+    #[test]
+    fn pattern_matches_numerals() {
+        for scheme in [
+            NumberingScheme::Roman,
+            NumberingScheme::Arabic,
+            NumberingScheme::ZeroPadded(2),
+            NumberingScheme::Letters,
+            words(),
+        ] {
+            let regex = Regex::new(&format!("^{}$", scheme.pattern())).unwrap();
+            for numeral in (1..=30).filter_map(|i| scheme.format(i)) {
+                assert!(regex.is_match(&numeral), "{numeral} is not matched");
+            }
+            assert!(!regex.is_match(""));
+        }
+
+        let regex = Regex::new(&format!("^{}$", words().pattern())).unwrap();
+        assert!(!regex.is_match("Beta 2"));
+        assert!(!regex.is_match("CC"));
+        let regex = Regex::new(&format!("^{}$", NumberingScheme::ZeroPadded(2).pattern())).unwrap();
+        assert!(!regex.is_match("7"));
+    }
+
+    #[test]
+    fn invalid_schemes_are_rejected() {
+        assert!(NumberingScheme::ZeroPadded(0).validate().is_err());
+        assert!(NumberingScheme::ZeroPadded(MAX_WIDTH + 1)
+            .validate()
+            .is_err());
+        assert!(NumberingScheme::ZeroPadded(MAX_WIDTH).validate().is_ok());
+        assert!(NumberingScheme::Words(Vec::new()).validate().is_err());
+        assert!(NumberingScheme::Words(vec!["A".into(), " ".into()])
+            .validate()
+            .is_err());
+        assert!(NumberingScheme::Words(vec!["A".into(), "A".into()])
+            .validate()
+            .is_err());
+        assert!(words().validate().is_ok());
+    }
+}
@@ -0,0 +1,91 @@
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::{Components, OpenApi as Document};
+use utoipa::{Modify, OpenApi};
+
+use crate::api::{
+    ApiAugmentation, ApiChannel, ApiChannelDetails, ApiClient, ApiClientDetails, ApiCodec,
+    ApiServer, ApiTree,
+};
+use crate::augmentation::{AugmentationOptions, ConnectionState, ForeignChannel, ForeignGroup};
+use crate::errors::ErrorBody;
+use crate::helper::{Channel, Client};
+use crate::live::TreeChange;
+use crate::numbering::NumberingScheme;
+use crate::planner::{Action, ChannelRef, Compaction};
+use crate::requests::Success;
+use crate::template::NameTemplate;
+use crate::tree::Tree;
+
+/// OpenAPI document of all JSON endpoints, generated from the types they
+/// exchange and the `#[utoipa::path]` attributes next to the routes.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Marungu Sunbird",
+        description = "JSON endpoints of the web interface and the versioned API for bots."
+    ),
+    paths(
+        crate::requests::augment,
+        crate::requests::abridge,
+        crate::requests::resync,
+        crate::requests::change_prefix,
+        crate::requests::plan,
+        crate::requests::foreign_groups,
+        crate::requests::adopt,
+        crate::requests::connection,
+        crate::live::events,
+        crate::api::server,
+        crate::api::tree,
+        crate::api::channel,
+        crate::api::client,
+        crate::api::augmentations,
+    ),
+    components(schemas(
+        Success,
+        ErrorBody,
+        AugmentationOptions,
+        NameTemplate,
+        NumberingScheme,
+        Compaction,
+        Action,
+        ChannelRef,
+        ForeignGroup,
+        ForeignChannel,
+        ConnectionState,
+        TreeChange,
+        Tree,
+        Channel,
+        Client,
+        ApiServer,
+        ApiTree,
+        ApiChannel,
+        ApiClient,
+        ApiCodec,
+        ApiChannelDetails,
+        ApiClientDetails,
+        ApiAugmentation,
+    )),
+    modifiers(&SecuritySchemes)
+)]
+pub struct ApiDoc;
+
+/// Declares the schemes referred to by the `security` of the paths.
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut Document) {
+        let components = openapi.components.get_or_insert_with(Components::new);
+        components.add_security_scheme(
+            "api_token",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("session"))),
+        );
+        components.add_security_scheme(
+            "csrf_token",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-CSRF-Token"))),
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use indexmap::IndexMap;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::auth::{ApiToken, Session};
+
+/// Role whose permissions every visitor has, logged in or not.
+pub const PUBLIC_ROLE: &str = "public";
+
+/// Action in the web interface that can be granted to a role.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// View the tree, channels, augmentations and their plans.
+    ViewTree,
+    /// View the details of a client.
+    ViewClient,
+    /// View the IP of a client.
+    ViewClientIp,
+    Augment,
+    Abridge,
+    ChangePrefix,
+    Resync,
+    /// Adopt a group of channels managed by someone else.
+    Adopt,
+}
+
+/// Permissions of every role, keyed by role name.
+pub struct Roles(IndexMap<String, Vec<Permission>>);
+
+impl Roles {
+    pub fn new(roles: IndexMap<String, Vec<Permission>>) -> Self {
+        Self(roles)
+    }
+
+    /// Roles used if the config does not define any.
+    pub fn defaults() -> IndexMap<String, Vec<Permission>> {
+        use Permission::*;
+        IndexMap::from([
+            (PUBLIC_ROLE.to_string(), vec![ViewTree, ViewClient]),
+            ("viewer".to_string(), vec![ViewTree, ViewClient]),
+            (
+                "moderator".to_string(),
+                vec![ViewTree, ViewClient, ChangePrefix, Resync],
+            ),
+            (
+                "admin".to_string(),
+                vec![
+                    ViewTree,
+                    ViewClient,
+                    ViewClientIp,
+                    Augment,
+                    Abridge,
+                    ChangePrefix,
+                    Resync,
+                    Adopt,
+                ],
+            ),
+        ])
+    }
+
+    /// Permissions granted by the public role and all of the `roles`.
+    fn permissions(&self, roles: &[String]) -> HashSet<Permission> {
+        std::iter::once(PUBLIC_ROLE)
+            .chain(roles.iter().map(String::as_str))
+            .filter_map(|role| self.0.get(role))
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
+
+/// Permissions of the visitor of a request. As request guard it never fails,
+/// so read-only routes can use it to hide parts of a page. Routes that need a
+/// permission use one of the guards below instead.
+pub struct Access {
+    /// Logged in user or owner of the API token, `None` for anonymous
+    /// visitors.
+    pub user: Option<String>,
+    pub permissions: HashSet<Permission>,
+}
+
+impl Access {
+    pub fn allows(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    async fn require(req: &Request<'_>, permission: Permission) -> Outcome<Self, ()> {
+        let access = match req.guard::<Access>().await {
+            Outcome::Success(access) => access,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        if access.allows(permission) {
+            Outcome::Success(access)
+        } else if access.user.is_none() {
+            Outcome::Error((Status::Unauthorized, ()))
+        } else {
+            Outcome::Error((Status::Forbidden, ()))
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Access {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        // API clients only get the role of their token, never a session
+        let (user, roles) = if let Some(token) = req.guard::<ApiToken>().await.succeeded() {
+            (Some(token.name), vec![token.role])
+        } else if let Some(session) = req.guard::<Session>().await.succeeded() {
+            (Some(session.user), session.roles)
+        } else {
+            (None, Vec::new())
+        };
+        let permissions = req
+            .rocket()
+            .state::<Roles>()
+            .map(|r| r.permissions(&roles))
+            .unwrap_or_default();
+        Outcome::Success(Access { user, permissions })
+    }
+}
+
+/// Declares a request guard for each permission, which fails with 401 for
+/// anonymous visitors and 403 for logged in users lacking it.
+macro_rules! permission_guards {
+    ($($guard:ident => $permission:ident),* $(,)?) => {$(
+        pub struct $guard(pub Access);
+
+        #[rocket::async_trait]
+        impl<'r> FromRequest<'r> for $guard {
+            type Error = ();
+
+            async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+                Access::require(req, Permission::$permission).await.map($guard)
+            }
+        }
+    )*};
+}
+
+permission_guards! {
+    CanViewTree => ViewTree,
+    CanViewClient => ViewClient,
+    CanAugment => Augment,
+    CanAbridge => Abridge,
+    CanChangePrefix => ChangePrefix,
+    CanResync => Resync,
+    CanAdopt => Adopt,
+}
@@ -0,0 +1,571 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::mem;
+use ts3_query_api::definitions::ChannelListEntry;
+use utoipa::ToSchema;
+
+use crate::augmentation::Augmentation;
+use crate::errors::Error;
+
+/// How empty instances in front of occupied ones are closed.
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Compaction {
+    /// Gaps are kept, nobody is ever moved and spares are only trimmed from
+    /// the end of the group.
+    Never,
+    /// Gaps are closed once the group was left alone for a while.
+    OnIdle,
+    /// Gaps are closed as soon as they appear.
+    #[default]
+    Immediate,
+}
+
+impl Compaction {
+    /// Whether gaps should be closed, `idle` tells if the group is idle.
+    pub fn compacts(&self, idle: bool) -> bool {
+        match self {
+            Compaction::Never => false,
+            Compaction::OnIdle => idle,
+            Compaction::Immediate => true,
+        }
+    }
+}
+
+/// Channel of the server as far as planning is concerned.
+pub trait Instance {
+    fn id(&self) -> i32;
+    fn name(&self) -> &str;
+    /// Channel the instance is sorted after.
+    fn order(&self) -> i32;
+    /// Whether the instance or one of its sub-channels has clients.
+    fn is_occupied(&self) -> bool;
+}
+
+impl Instance for ChannelListEntry {
+    fn id(&self) -> i32 {
+        self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn order(&self) -> i32 {
+        self.order
+    }
+
+    fn is_occupied(&self) -> bool {
+        self.total_clients > 0
+    }
+}
+
+/// Channel that either exists already or is created by an earlier action of the plan.
+#[derive(Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelRef {
+    Existing(i32),
+    /// Index of the `Create` action among all `Create` actions of the plan.
+    Created(usize),
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    Create {
+        name: String,
+        after: Option<ChannelRef>,
+    },
+    Rename {
+        channel: i32,
+        from: String,
+        to: String,
+    },
+    Reorder {
+        channel: i32,
+        after: i32,
+    },
+    MoveClients {
+        from: i32,
+        to: i32,
+    },
+    Delete {
+        channel: i32,
+        name: String,
+    },
+}
+
+/// Plans the actions bringing the instances of an augmentation into shape:
+/// occupied instances first, followed by `min_empty` to `max_empty` empty
+/// spares within the instance caps, all named according to the template.
+/// Unless `compact` is set, empty instances in front of occupied ones are
+/// kept and count as occupied.
+///
+/// If there are no instances at all, the whole group is created.
+pub fn plan<C: Instance>(
+    augmentation: &Augmentation,
+    instances: &[&C],
+    compact: bool,
+) -> Result<Vec<Action>, Error> {
+    let mut actions = Vec::new();
+
+    if instances.is_empty() {
+        let total = augmentation.target_instances(1, 0);
+        for i in 0..total {
+            actions.push(Action::Create {
+                name: augmentation.instance_name(i, total)?,
+                after: i.checked_sub(1).map(ChannelRef::Created),
+            });
+        }
+        return Ok(actions);
+    }
+
+    let mut instances = instances.to_vec();
+
+    // fill empty instances in front of occupied ones with the last occupied
+    // instance, the clients stay in their channel while it is moved
+    let mut replaced = HashMap::new();
+    while compact {
+        let gap = instances.iter().position(|c| !c.is_occupied());
+        let last_occupied = instances.iter().rposition(|c| c.is_occupied());
+        let (gap, last_occupied) = match (gap, last_occupied) {
+            (Some(gap), Some(last_occupied)) if gap < last_occupied => (gap, last_occupied),
+            _ => break,
+        };
+
+        let empty = instances[gap];
+        // the predecessor of the empty channel may have been replaced already
+        let after = *replaced.get(&empty.order()).unwrap_or(&empty.order());
+        let moved = instances.remove(last_occupied);
+        actions.push(Action::Delete {
+            channel: empty.id(),
+            name: empty.name().to_string(),
+        });
+        // the name is fixed below, once the name of the empty channel is free
+        actions.push(Action::Reorder {
+            channel: moved.id(),
+            after,
+        });
+        replaced.insert(empty.id(), moved.id());
+        instances[gap] = moved;
+    }
+
+    // the first instance is kept even if the whole group is empty, remaining
+    // gaps are treated like occupied instances
+    let occupied = instances
+        .iter()
+        .rposition(|c| c.is_occupied())
+        .map_or(1, |last| last + 1);
+    let mut total = augmentation.target_instances(occupied, instances.len());
+
+    if total < instances.len() {
+        // delete surplus spares from the end
+        for channel in instances.split_off(total) {
+            actions.push(Action::Delete {
+                channel: channel.id(),
+                name: channel.name().to_string(),
+            });
+        }
+    }
+
+    if total > instances.len() && augmentation.is_exhausted(total - 1) {
+        warn!(
+            "Numbering of augmentation {} is exhausted, not adding more channels",
+            augmentation.identifier
+        );
+        while total > instances.len() && augmentation.is_exhausted(total - 1) {
+            total -= 1;
+        }
+    }
+
+    // rename the existing channels first, so the new ones are appended to a
+    // group that already has its final names
+    let mut renames = Vec::new();
+    for (i, channel) in instances.iter().enumerate() {
+        let name = augmentation.instance_name(i, total)?;
+        if name != channel.name() {
+            renames.push((channel.id(), channel.name().to_string(), name));
+        }
+    }
+    // siblings must have distinct names, so a rename waits until no other
+    // instance holds its target, and a cycle is broken with a temporary name
+    while !renames.is_empty() {
+        let free = renames
+            .iter()
+            .position(|(_, _, to)| !renames.iter().any(|(_, from, _)| from == to));
+        match free {
+            Some(i) => {
+                let (channel, from, to) = renames.remove(i);
+                actions.push(Action::Rename { channel, from, to });
+            }
+            None => {
+                let (channel, from, _) = &mut renames[0];
+                let temporary = format!("~{channel}");
+                actions.push(Action::Rename {
+                    channel: *channel,
+                    from: mem::replace(from, temporary.clone()),
+                    to: temporary,
+                });
+            }
+        }
+    }
+
+    let last = instances[instances.len() - 1].id();
+    for (created, i) in (instances.len()..total).enumerate() {
+        actions.push(Action::Create {
+            name: augmentation.instance_name(i, total)?,
+            after: Some(match created {
+                0 => ChannelRef::Existing(last),
+                _ => ChannelRef::Created(created - 1),
+            }),
+        });
+    }
+
+    Ok(actions)
+}
+
+/// Whether there are empty instances in front of occupied ones.
+pub fn has_gaps<C: Instance>(instances: &[&C]) -> bool {
+    let first_empty = instances.iter().position(|c| !c.is_occupied());
+    let last_occupied = instances.iter().rposition(|c| c.is_occupied());
+    matches!((first_empty, last_occupied), (Some(gap), Some(last)) if gap < last)
+}
+
+/// Plans the actions merging all instances back into the first one.
+pub fn plan_abridge<C: Instance>(augmentation: &Augmentation, instances: &[&C]) -> Vec<Action> {
+    let mut actions = Vec::new();
+    let Some((first, others)) = instances.split_first() else {
+        return actions;
+    };
+
+    for channel in others.iter().filter(|c| c.is_occupied()) {
+        actions.push(Action::MoveClients {
+            from: channel.id(),
+            to: first.id(),
+        });
+    }
+    for channel in others {
+        actions.push(Action::Delete {
+            channel: channel.id(),
+            name: channel.name().to_string(),
+        });
+    }
+    if first.name() != augmentation.identifier {
+        actions.push(Action::Rename {
+            channel: first.id(),
+            from: first.name().to_string(),
+            to: augmentation.identifier.clone(),
+        });
+    }
+
+    actions
+}
+
+/// Plans the deletion of the empty instances left behind by a taken over group.
+pub fn plan_cleanup<C: Instance>(instances: &[&C]) -> Vec<Action> {
+    instances
+        .iter()
+        .filter(|c| !c.is_occupied())
+        .map(|c| Action::Delete {
+            channel: c.id(),
+            name: c.name().to_string(),
+        })
+        .collect()
+}
+
+impl Display for ChannelRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelRef::Existing(id) => write!(f, "channel {id}"),
+            ChannelRef::Created(index) => write!(f, "created channel {}", index + 1),
+        }
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Create { name, after } => match after {
+                Some(after) => write!(f, "create \"{name}\" after {after}"),
+                None => write!(f, "create \"{name}\""),
+            },
+            Action::Rename { channel, from, to } => {
+                write!(f, "rename channel {channel} from \"{from}\" to \"{to}\"")
+            }
+            Action::Reorder { channel, after } => {
+                write!(f, "move channel {channel} after channel {after}")
+            }
+            Action::MoveClients { from, to } => {
+                write!(f, "move clients from channel {from} to channel {to}")
+            }
+            Action::Delete { channel, name } => write!(f, "delete channel {channel} \"{name}\""),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::numbering::NumberingScheme;
+    use crate::template::NameTemplate;
+    use std::sync::OnceLock;
+
+    struct Channel {
+        id: i32,
+        order: i32,
+        name: String,
+        occupied: bool,
+    }
+
+    impl Instance for Channel {
+        fn id(&self) -> i32 {
+            self.id
+        }
+
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn order(&self) -> i32 {
+            self.order
+        }
+
+        fn is_occupied(&self) -> bool {
+            self.occupied
+        }
+    }
+
+    fn augmentation() -> Augmentation {
+        Augmentation {
+            identifier: "Lobby".to_string(),
+            parent: 0,
+            prefix: None,
+            template: NameTemplate::default(),
+            numbering: NumberingScheme::Arabic,
+            min_empty: 1,
+            max_empty: 1,
+            min_instances: 0,
+            max_instances: None,
+            sync: false,
+            compaction: Compaction::Immediate,
+            permissions: Vec::new(),
+            properties: Vec::new(),
+            sub_channels: Vec::new(),
+            channels: Vec::new(),
+            taken_over: None,
+            regex: OnceLock::new(),
+        }
+    }
+
+    /// Instances "Lobby 1" to "Lobby n" with ids 1 to n, sorted by id.
+    fn group(occupied: &[bool]) -> Vec<Channel> {
+        occupied
+            .iter()
+            .zip(1..)
+            .map(|(&occupied, id)| Channel {
+                id,
+                order: id - 1,
+                name: format!("Lobby {id}"),
+                occupied,
+            })
+            .collect()
+    }
+
+    fn describe(actions: Vec<Action>) -> Vec<String> {
+        actions.iter().map(ToString::to_string).collect()
+    }
+
+    fn run(augmentation: &Augmentation, occupied: &[bool], compact: bool) -> Vec<String> {
+        let channels = group(occupied);
+        let instances = channels.iter().collect::<Vec<_>>();
+        describe(plan(augmentation, &instances, compact).unwrap())
+    }
+
+    #[test]
+    fn empty_group_is_created() {
+        let instances: [&Channel; 0] = [];
+        let actions = describe(plan(&augmentation(), &instances, true).unwrap());
+        assert_eq!(
+            actions,
+            [
+                "create \"Lobby 1\"",
+                "create \"Lobby 2\" after created channel 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn settled_group_is_left_alone() {
+        assert!(run(&augmentation(), &[true, false], true).is_empty());
+        assert!(run(&augmentation(), &[false, false], true).is_empty());
+    }
+
+    #[test]
+    fn spare_is_added() {
+        assert_eq!(
+            run(&augmentation(), &[true, true], true),
+            ["create \"Lobby 3\" after channel 2"]
+        );
+    }
+
+    #[test]
+    fn gap_is_closed() {
+        assert_eq!(
+            run(&augmentation(), &[true, false, true], true),
+            [
+                "delete channel 2 \"Lobby 2\"",
+                "move channel 3 after channel 1",
+                "rename channel 3 from \"Lobby 3\" to \"Lobby 2\"",
+                "create \"Lobby 3\" after channel 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn renames_wait_for_their_names() {
+        let mut channels = group(&[true, true, false]);
+        channels[0].name = "Lobby 2".to_string();
+        channels[1].name = "Lobby 3".to_string();
+        channels[2].name = "Lobby 1".to_string();
+        let instances = channels.iter().collect::<Vec<_>>();
+        assert_eq!(
+            describe(plan(&augmentation(), &instances, true).unwrap()),
+            [
+                "rename channel 1 from \"Lobby 2\" to \"~1\"",
+                "rename channel 2 from \"Lobby 3\" to \"Lobby 2\"",
+                "rename channel 3 from \"Lobby 1\" to \"Lobby 3\"",
+                "rename channel 1 from \"~1\" to \"Lobby 1\"",
+            ]
+        );
+
+        channels[1].name = "Lobby 4".to_string();
+        let instances = channels.iter().collect::<Vec<_>>();
+        assert_eq!(
+            describe(plan(&augmentation(), &instances, true).unwrap()),
+            [
+                "rename channel 3 from \"Lobby 1\" to \"Lobby 3\"",
+                "rename channel 1 from \"Lobby 2\" to \"Lobby 1\"",
+                "rename channel 2 from \"Lobby 4\" to \"Lobby 2\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn gap_is_kept_without_compaction() {
+        assert_eq!(
+            run(&augmentation(), &[true, false, true], false),
+            ["create \"Lobby 4\" after channel 3"]
+        );
+    }
+
+    #[test]
+    fn gaps_are_detected() {
+        let channels = group(&[true, false, true]);
+        assert!(has_gaps(&channels.iter().collect::<Vec<_>>()));
+        let channels = group(&[true, true, false, false]);
+        assert!(!has_gaps(&channels.iter().collect::<Vec<_>>()));
+        let channels = group(&[false, false]);
+        assert!(!has_gaps(&channels.iter().collect::<Vec<_>>()));
+    }
+
+    #[test]
+    fn surplus_spares_are_deleted() {
+        assert_eq!(
+            run(&augmentation(), &[true, false, false, false], true),
+            [
+                "delete channel 3 \"Lobby 3\"",
+                "delete channel 4 \"Lobby 4\""
+            ]
+        );
+    }
+
+    #[test]
+    fn spares_within_bounds_are_kept() {
+        let mut augmentation = augmentation();
+        augmentation.min_empty = 1;
+        augmentation.max_empty = 3;
+        assert!(run(&augmentation, &[true, false, false], true).is_empty());
+        assert_eq!(
+            run(&augmentation, &[true, false, false, false, false], true),
+            ["delete channel 5 \"Lobby 5\""]
+        );
+    }
+
+    #[test]
+    fn min_instances_are_created() {
+        let mut augmentation = augmentation();
+        augmentation.min_instances = 4;
+        assert_eq!(
+            run(&augmentation, &[true, false], true),
+            [
+                "create \"Lobby 3\" after channel 2",
+                "create \"Lobby 4\" after created channel 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn max_instances_cap_spares() {
+        let mut augmentation = augmentation();
+        augmentation.max_instances = Some(2);
+        assert!(run(&augmentation, &[true, true], true).is_empty());
+        assert_eq!(
+            run(&augmentation, &[true, false, false], true),
+            ["delete channel 3 \"Lobby 3\""]
+        );
+    }
+
+    #[test]
+    fn max_instances_keep_occupied() {
+        let mut augmentation = augmentation();
+        augmentation.max_instances = Some(2);
+        assert!(run(&augmentation, &[true, true, true], true).is_empty());
+    }
+
+    #[test]
+    fn exhausted_numbering_stops_growing() {
+        let mut augmentation = augmentation();
+        augmentation.numbering = NumberingScheme::Words(vec!["1".to_string(), "2".to_string()]);
+        assert!(run(&augmentation, &[true, true], true).is_empty());
+        assert_eq!(
+            run(&augmentation, &[true], true),
+            ["create \"Lobby 2\" after channel 1"]
+        );
+    }
+
+    #[test]
+    fn abridge_merges_into_first() {
+        let channels = group(&[true, false, true]);
+        let instances = channels.iter().collect::<Vec<_>>();
+        assert_eq!(
+            describe(plan_abridge(&augmentation(), &instances)),
+            [
+                "move clients from channel 3 to channel 1",
+                "delete channel 2 \"Lobby 2\"",
+                "delete channel 3 \"Lobby 3\"",
+                "rename channel 1 from \"Lobby 1\" to \"Lobby\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn abridge_of_empty_group_does_nothing() {
+        let instances: [&Channel; 0] = [];
+        assert!(plan_abridge(&augmentation(), &instances).is_empty());
+    }
+
+    #[test]
+    fn cleanup_deletes_empty_instances() {
+        let channels = group(&[false, true, false]);
+        let instances = channels.iter().collect::<Vec<_>>();
+        assert_eq!(
+            describe(plan_cleanup(&instances)),
+            [
+                "delete channel 1 \"Lobby 1\"",
+                "delete channel 3 \"Lobby 3\""
+            ]
+        );
+    }
+}
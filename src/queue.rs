@@ -0,0 +1,78 @@
+use log::error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::time::{interval, sleep_until, Instant};
+
+use crate::augmentation::AugmentationClient;
+
+/// Time without further requests after which a batch is reconciled.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+/// Upper bound for delaying a batch while requests keep arriving.
+const MAX_DELAY: Duration = Duration::from_secs(2);
+/// Interval of the passes that catch up on groups waiting to be compacted on idle
+/// and pick up template edits, which the server does not announce for permissions.
+const IDLE_CHECK: Duration = Duration::from_secs(60);
+
+/// Coalesces bursts of reconciliation requests into a single pass.
+///
+/// All passes run on one task, so they never overlap.
+pub struct ReconciliationQueue {
+    /// Whether the templates of the augmentations should be synced as well.
+    sender: UnboundedSender<bool>,
+}
+
+impl ReconciliationQueue {
+    pub fn spawn(client: Arc<AugmentationClient>) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+
+        let idle = sender.clone();
+        tokio::spawn(async move {
+            let mut interval = interval(IDLE_CHECK);
+            loop {
+                interval.tick().await;
+                if idle.send(true).is_err() {
+                    break;
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            while let Some(mut sync) = receiver.recv().await {
+                // wait until the burst settles, but not forever
+                let deadline = Instant::now() + MAX_DELAY;
+                loop {
+                    let wait = (Instant::now() + DEBOUNCE).min(deadline);
+                    tokio::select! {
+                        request = receiver.recv() => match request {
+                            Some(request) => sync |= request,
+                            None => break,
+                        },
+                        _ = sleep_until(wait) => break,
+                    }
+                }
+
+                if sync {
+                    if let Err(e) = client.sync_templates().await {
+                        error!("Could not sync augmentation templates: {e}");
+                    }
+                }
+                if let Err(e) = client.update_augmented_channels().await {
+                    error!("Could not update augmented channels: {e}");
+                }
+            }
+        });
+
+        Self { sender }
+    }
+
+    /// Requests a reconciliation of the augmentations touched since the last pass.
+    pub fn request(&self) {
+        let _ = self.sender.send(false);
+    }
+
+    /// Requests a reconciliation that also pushes edits of first instances.
+    pub fn request_sync(&self) {
+        let _ = self.sender.send(true);
+    }
+}
@@ -2,20 +2,33 @@ use base64::{engine::general_purpose, Engine as _};
 use chrono::prelude::DateTime;
 use chrono::Utc;
 use log::{error, info};
+use rocket::form::{Form, FromForm};
 use rocket::fs::NamedFile;
+use rocket::http::{CookieJar, Status};
 use rocket::response::Redirect;
 use rocket::serde::json::Json;
-use rocket::{get, post, State};
+use rocket::{get, post, Responder, State};
 use rocket_dyn_templates::Template;
-use serde::Deserialize;
+use serde::Serialize;
 use serde_json::json;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
 use std::time::{Duration, UNIX_EPOCH};
 use ts3_query_api::definitions::Codec;
-
-use crate::augmentation::{AugmentationClient, AugmentationPrefix};
-use crate::helper::{format_duration, init_badges};
+use ts3_query_api::error::QueryError;
+use utoipa::ToSchema;
+
+use crate::augmentation::{split_key, AugmentationOptions, ConnectionState, ForeignGroup};
+use crate::auth::{Challenges, Session, Users};
+use crate::csrf::{Csrf, CsrfChecked};
+use crate::errors::{Error, ErrorBody};
+use crate::helper::{format_duration, init_badges, listen_addresses, ClientMetaData};
+use crate::permissions::{
+    CanAbridge, CanAdopt, CanAugment, CanChangePrefix, CanResync, CanViewClient, CanViewTree,
+    Permission,
+};
+use crate::planner::Action;
+use crate::servers::Servers;
 use crate::tree::build_tree;
 
 // ===============
@@ -23,7 +36,9 @@ use crate::tree::build_tree;
 // ===============
 
 #[get("/badges/<badge>")]
-pub async fn badge(client: &State<Arc<AugmentationClient>>, badge: &str) -> Option<NamedFile> {
+pub async fn badge(servers: &State<Servers>, badge: &str) -> Option<NamedFile> {
+    // the update time is tracked in the state of the first server
+    let client = servers.first()?;
     let mut config = client.config.lock().await;
 
     if config.internal.last_badge_update + 3600 < chrono::Utc::now().timestamp() as u64 {
@@ -66,96 +81,169 @@ pub async fn favicon() -> Option<NamedFile> {
 // UI endpoints
 // ============
 
+/// Reason a page is not shown: it is found elsewhere, or the virtual server
+/// could not provide its content.
+#[derive(Responder)]
+pub enum PageError {
+    Redirect(Redirect),
+    /// Shown by the catchers, which tell a lost connection apart.
+    Failed(Status),
+}
+
+impl From<Error> for PageError {
+    fn from(e: Error) -> Self {
+        error!("Could not render page: {e}");
+        PageError::Failed(e.status())
+    }
+}
+
+impl From<QueryError> for PageError {
+    fn from(e: QueryError) -> Self {
+        Error::from(e).into()
+    }
+}
+
 #[get("/")]
-pub async fn tree(client: &State<Arc<AugmentationClient>>) -> Template {
+pub async fn servers(_access: CanViewTree, servers: &State<Servers>) -> Result<Template, Redirect> {
+    // skip the picker if there is nothing to pick
+    if servers.len() == 1 {
+        if let Some((server, _)) = servers.iter().next() {
+            return Err(Redirect::to(format!("/s/{server}")));
+        }
+    }
+
+    Ok(Template::render(
+        "servers",
+        json!({ "servers": server_list(servers).await }),
+    ))
+}
+
+/// Name, connection and state of every virtual server, for server selections.
+async fn server_list(servers: &Servers) -> Vec<serde_json::Value> {
+    let mut list = Vec::new();
+    for (server, client) in servers.iter() {
+        // disconnected servers are only listed with their connection state
+        let name = match client.client() {
+            Ok(query) => match query.server_info().await {
+                Ok(info) => Some(info.name.to_string()),
+                Err(e) => {
+                    error!("Could not get info of virtual server {server}: {e}");
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+        let host = client.config.lock().await.name.clone();
+        list.push(json!({
+            "id": server,
+            "name": name,
+            "host": host,
+            "connection": client.connection(),
+        }));
+    }
+
+    list
+}
+
+#[get("/s/<server>")]
+pub async fn tree(
+    servers: &State<Servers>,
+    server: &str,
+    access: CanViewTree,
+    cookies: &CookieJar<'_>,
+) -> Result<Template, PageError> {
+    let Some(client) = servers.get(server) else {
+        return Err(PageError::Redirect(Redirect::to("/")));
+    };
     let config = client.config.lock().await;
-    let tree = match build_tree(&client.client, &config.internal.augmentations).await {
-        Ok(tree) => tree,
+    let tree = build_tree(&client.client()?, &config.internal.augmentations).await?;
+    drop(config);
+
+    let foreign = match client.foreign_groups().await {
+        Ok(groups) => groups.len(),
         Err(e) => {
-            error!("Could not build tree: {e}");
-            panic!("Trigger Rocket 500 error")
+            error!("Could not find foreign groups: {e}");
+            0
         }
     };
-    drop(config);
 
-    let server = client.client.server_info().await.unwrap();
+    let info = client.client()?.server_info().await?;
 
-    let created = UNIX_EPOCH + Duration::from_secs(server.created);
+    let created = UNIX_EPOCH + Duration::from_secs(info.created);
     // Create DateTime from SystemTime
     let created = DateTime::<Utc>::from(created);
     // Formats the combined date and time with the specified format string.
     let created = created.format("%d.%m.%Y %H:%M").to_string();
 
-    Template::render(
+    Ok(Template::render(
         "index",
         json!({
             "tree": tree,
-            "name": server.name.to_string(),
+            "server": server,
+            "user": access.0.user,
+            "permissions": access.0.permissions,
+            "csrf": Csrf::token(cookies),
+            "foreign": foreign,
+            "name": info.name.to_string(),
             "properties": [
-                {"name": "VS ID", "value": server.id},
-                {"name": "Welcome Message", "value": server.welcome_message},
+                {"name": "VS ID", "value": info.id},
+                {"name": "Welcome Message", "value": info.welcome_message},
                 {"name": "Created", "value": created},
-                {"name": "Uptime", "value": format_duration(server.uptime as i64)},
-                {"name": "Max Clients", "value": server.max_clients},
-                {"name": "Listen", "value": server
-                .ip
-                .split(", ")
-                .map(|ip| {
-                    if ip.contains(':') {
-                        format!("[{}]:{}", ip, server.port)
-                    } else {
-                        format!("{}:{}", ip, server.port)
-                    }
-                })
-                .collect::<Vec<_>>()
-                .join(", ")},
-                {"name": "Version", "value": server.version},
-                {"name": "Platform", "value": server.platform},
-                {"name": "Capabilities", "value": server.capability_extensions}
+                {"name": "Uptime", "value": format_duration(info.uptime as i64)},
+                {"name": "Max Clients", "value": info.max_clients},
+                {"name": "Listen", "value": listen_addresses(&info.ip, info.port).join(", ")},
+                {"name": "Version", "value": info.version},
+                {"name": "Platform", "value": info.platform},
+                {"name": "Capabilities", "value": info.capability_extensions}
             ],
         }),
-    )
+    ))
 }
 
-#[get("/channel/<id>")]
+#[get("/s/<server>/channel/<id>")]
 pub async fn channel(
-    client: &State<Arc<AugmentationClient>>,
+    servers: &State<Servers>,
+    server: &str,
     id: i32,
-) -> Result<Template, Redirect> {
-    let config = client.config.lock().await;
-    let tree = match build_tree(&client.client, &config.internal.augmentations).await {
-        Ok(tree) => tree,
-        Err(e) => {
-            error!("Could not build tree: {e}");
-            panic!("Trigger Rocket 500 error")
-        }
+    access: CanViewTree,
+    cookies: &CookieJar<'_>,
+) -> Result<Template, PageError> {
+    let Some(client) = servers.get(server) else {
+        return Err(PageError::Redirect(Redirect::to("/")));
     };
-    let channel = match client.client.channel_info(id).await {
+    let config = client.config.lock().await;
+    let tree = build_tree(&client.client()?, &config.internal.augmentations).await?;
+    let channel = match client.client()?.channel_info(id).await {
         Ok(channel) => channel,
-        Err(_) => return Err(Redirect::to("/")),
+        Err(_) => return Err(PageError::Redirect(Redirect::to(format!("/s/{server}")))),
     };
 
-    // check if channel is augmented, if so redirect to augmentation
-    if let Some(augmentation) = config
-        .internal
-        .augmentations
-        .iter()
-        .find(|a| a.is_instance(&channel.name))
-    {
-        let redirection = format!(
-            "/augmentation/{}",
-            general_purpose::URL_SAFE_NO_PAD.encode(&augmentation.identifier)
-        );
-        info!("Redirecting to {redirection}");
-        return Err(Redirect::to(redirection));
-    }
-
     drop(config);
 
+    // check if channel is augmented, if so redirect to augmentation
+    let parent = match tree.channel_map.get(&id) {
+        Some(c) => {
+            if let Some(key) = &c.augmentation_id {
+                let redirection = format!(
+                    "/s/{server}/augmentation/{}",
+                    general_purpose::URL_SAFE_NO_PAD.encode(key)
+                );
+                info!("Redirecting to {redirection}");
+                return Err(PageError::Redirect(Redirect::to(redirection)));
+            }
+            c.parent_id
+        }
+        None => return Err(PageError::Redirect(Redirect::to(format!("/s/{server}")))),
+    };
+
     Ok(Template::render(
         "channel",
         json!({
             "tree": tree,
+            "server": server,
+            "user": access.0.user,
+            "permissions": access.0.permissions,
+            "csrf": Csrf::token(cookies),
             "properties": [
                 {"name": "Topic", "value": channel.topic},
                 {"name": "Description", "value": channel.description},
@@ -179,85 +267,95 @@ pub async fn channel(
                 }},
                 {"name": "Needed Talk Power", "value": channel.needed_talk_power},
             ],
+            "key": format!("{parent}/{}", channel.name),
             "name": channel.name,
             "id": id,
         }),
     ))
 }
 
-#[get("/augmentation/<name>")]
+#[get("/s/<server>/augmentation/<name>")]
 pub async fn augmentation(
-    client: &State<Arc<AugmentationClient>>,
+    servers: &State<Servers>,
+    server: &str,
     name: String,
-) -> Result<Template, Redirect> {
+    access: CanViewTree,
+    cookies: &CookieJar<'_>,
+) -> Result<Template, PageError> {
+    let Some(client) = servers.get(server) else {
+        return Err(PageError::Redirect(Redirect::to("/")));
+    };
     let name = match String::from_utf8(
         match general_purpose::URL_SAFE_NO_PAD.decode(name.as_bytes()) {
             Ok(name) => name,
             Err(e) => {
                 error!("Could not decode augmentation name: {e}");
-                return Err(Redirect::to("/"));
+                return Err(PageError::Redirect(Redirect::to(format!("/s/{server}"))));
             }
         },
     ) {
         Ok(name) => name,
         Err(e) => {
             error!("Could not decode augmentation name: {e}");
-            return Err(Redirect::to("/"));
+            return Err(PageError::Redirect(Redirect::to(format!("/s/{server}"))));
         }
     };
 
     let config = client.config.lock().await;
-    let tree = match build_tree(&client.client, &config.internal.augmentations).await {
-        Ok(tree) => tree,
-        Err(e) => {
-            error!("Could not build tree: {e}");
-            panic!("Trigger Rocket 500 error")
-        }
-    };
+    let tree = build_tree(&client.client()?, &config.internal.augmentations).await?;
     // get data for augmentation
     let augmentation = match config
         .internal
         .augmentations
         .iter()
-        .find(|a| a.identifier == name)
+        .find(|a| a.key() == name)
     {
         Some(a) => a,
         None => {
-            // check if channel with name exists
+            // check if channel with name exists below the parent
+            let Some((parent, identifier)) = split_key(&name) else {
+                return Err(PageError::Redirect(Redirect::to(format!("/s/{server}"))));
+            };
             if let Some(channel) = client
-                .client
+                .client()?
                 .channel_list()
-                .await
-                .unwrap()
+                .await?
                 .iter()
-                .find(|c| c.name == name)
+                .find(|c| c.name == identifier && c.parent_id == parent)
             {
-                return Err(Redirect::to(format!("/channel/{}", channel.id)));
+                return Err(PageError::Redirect(Redirect::to(format!(
+                    "/s/{server}/channel/{}",
+                    channel.id
+                ))));
             }
-            return Err(Redirect::to("/"));
+            return Err(PageError::Redirect(Redirect::to(format!("/s/{server}"))));
         }
     };
-    // find first channel of augmentation
-    let channel = match client
-        .client
+    let instances = client
+        .client()?
         .channel_list()
-        .await
-        .unwrap()
-        .iter()
-        .find(|c| augmentation.is_instance(&c.name))
-    {
-        Some(c) => c.id,
-        None => return Err(Redirect::to("/")),
+        .await?
+        .into_iter()
+        .filter(|c| augmentation.owns(c))
+        .collect::<Vec<_>>();
+    // find first channel of augmentation, or the channel that took it over
+    let channel = match augmentation.taken_over.or(instances.first().map(|c| c.id)) {
+        Some(c) => c,
+        None => return Err(PageError::Redirect(Redirect::to(format!("/s/{server}")))),
     };
-    let channel = match client.client.channel_info(channel).await {
+    let channel = match client.client()?.channel_info(channel).await {
         Ok(channel) => channel,
-        Err(_) => return Err(Redirect::to("/")),
+        Err(_) => return Err(PageError::Redirect(Redirect::to(format!("/s/{server}")))),
     };
 
     Ok(Template::render(
         "augmentation",
         json!({
             "tree": tree,
+            "server": server,
+            "user": access.0.user,
+            "permissions": access.0.permissions,
+            "csrf": Csrf::token(cookies),
             "properties": [
                 {"name": "Topic", "value": channel.topic},
                 {"name": "Description", "value": channel.description},
@@ -282,169 +380,570 @@ pub async fn augmentation(
                 {"name": "Needed Talk Power", "value": channel.needed_talk_power},
             ],
             "augmentation": {
-                "first_prefix": augmentation.prefix.first,
-                "middle_prefix": augmentation.prefix.middle,
-                "last_prefix": augmentation.prefix.last,
+                "template": augmentation.template.0,
+                "numbering": augmentation.numbering.kind(),
+                "numbering_argument": augmentation.numbering.argument(),
+                "min_empty": augmentation.min_empty,
+                "max_empty": augmentation.max_empty,
+                "min_instances": augmentation.min_instances,
+                "max_instances": augmentation.max_instances,
+                "sync": augmentation.sync,
+                "compaction": augmentation.compaction,
+                "instances": instances.len(),
+                "at_capacity": augmentation.is_at_capacity(instances.len()),
+                "taken_over": augmentation.taken_over.is_some(),
             },
+            "key": augmentation.key(),
             "name": augmentation.identifier,
         }),
     ))
 }
 
-#[get("/client/<id>")]
+#[get("/s/<server>/client/<id>")]
 pub async fn client(
-    client: &State<Arc<AugmentationClient>>,
+    servers: &State<Servers>,
+    server: &str,
     id: i32,
-) -> Result<Template, Redirect> {
-    let config = client.config.lock().await;
-    let tree = match build_tree(&client.client, &config.internal.augmentations).await {
-        Ok(tree) => tree,
-        Err(e) => {
-            error!("Could not build tree: {e}");
-            panic!("Trigger Rocket 500 error")
-        }
+    access: CanViewClient,
+    cookies: &CookieJar<'_>,
+) -> Result<Template, PageError> {
+    let Some(client) = servers.get(server) else {
+        return Err(PageError::Redirect(Redirect::to("/")));
     };
+    let config = client.config.lock().await;
+    let tree = build_tree(&client.client()?, &config.internal.augmentations).await?;
     drop(config);
-    let client = match client.client.client_info(id).await {
+    let client = match client.client()?.client_info(id).await {
         Ok(client) => client,
         Err(_) => {
-            return Err(Redirect::to("/"));
+            return Err(PageError::Redirect(Redirect::to(format!("/s/{server}"))));
         }
     };
 
-    #[derive(Default, Deserialize)]
-    struct ClientMetaData {
-        _myts_token: Option<String>,
-        tag: Option<String>,
-        _updated: Option<u64>,
+    let meta_data = ClientMetaData::parse(client.meta_data.as_deref());
+
+    let mut properties = vec![
+        json!({"name": "Phonetic Name", "value": client.nickname_phonetic}),
+        json!({"name": "Description", "value": client.description}),
+        json!({"name": "MyTS ID", "value": meta_data.tag}),
+        json!({"name": "Total Connections", "value": client.total_connections}),
+        json!({"name": "DB ID", "value": client.database_id}),
+        json!({"name": "Version", "value": client.version}),
+        json!({"name": "Platform", "value": client.platform}),
+        json!({"name": "Talk Power", "value": client.talk_power}),
+    ];
+    // the IP is only shown to those allowed to see it
+    if access.0.allows(Permission::ViewClientIp) {
+        properties.push(json!({"name": "IP", "value": client.client_ip}));
     }
 
-    let meta_data = client.meta_data.clone();
-    // parse json meta data
-    let meta_data = match meta_data {
-        Some(meta_data) => match serde_json::from_str::<ClientMetaData>(&meta_data) {
-            Ok(meta_data) => meta_data,
-            Err(e) => {
-                error!("Could not parse meta data: {e}");
-                ClientMetaData::default()
-            }
-        },
-        None => ClientMetaData::default(),
-    };
-
     Ok(Template::render(
         "client",
         json!({
             "tree": tree,
-            "properties": [
-                {"name": "Phonetic Name", "value": client.nickname_phonetic},
-                {"name": "Description", "value": client.description},
-                {"name": "MyTS ID", "value": meta_data.tag},
-                {"name": "Total Connections", "value": client.total_connections},
-                {"name": "DB ID", "value": client.database_id},
-                {"name": "Version", "value": client.version},
-                {"name": "Platform", "value": client.platform},
-                {"name": "Talk Power", "value": client.talk_power},
-                {"name": "IP", "value": client.client_ip},
-            ],
+            "server": server,
+            "user": access.0.user,
+            "permissions": access.0.permissions,
+            "csrf": Csrf::token(cookies),
+            "properties": properties,
             "name": client.nickname,
         }),
     ))
 }
 
+#[get("/s/<server>/foreign")]
+pub async fn foreign(
+    servers: &State<Servers>,
+    server: &str,
+    access: CanViewTree,
+    cookies: &CookieJar<'_>,
+) -> Result<Template, PageError> {
+    let Some(client) = servers.get(server) else {
+        return Err(PageError::Redirect(Redirect::to("/")));
+    };
+    let config = client.config.lock().await;
+    let tree = build_tree(&client.client()?, &config.internal.augmentations).await?;
+    drop(config);
+
+    let groups = client.foreign_groups().await?;
+
+    Ok(Template::render(
+        "foreign",
+        json!({
+            "tree": tree,
+            "server": server,
+            "user": access.0.user,
+            "permissions": access.0.permissions,
+            "csrf": Csrf::token(cookies),
+            "groups": groups,
+        }),
+    ))
+}
+
+#[derive(FromForm)]
+pub struct Login {
+    user: String,
+    password: String,
+    csrf: String,
+}
+
+#[derive(FromForm)]
+pub struct TeamSpeakLogin {
+    server: String,
+    nickname: String,
+    csrf: String,
+}
+
+#[derive(FromForm)]
+pub struct LoginCode {
+    code: String,
+    csrf: String,
+}
+
+#[derive(FromForm)]
+pub struct Logout {
+    csrf: String,
+}
+
+#[get("/login?<failed>")]
+pub async fn login_page(
+    servers: &State<Servers>,
+    challenges: &State<Challenges>,
+    cookies: &CookieJar<'_>,
+    failed: Option<&str>,
+) -> Template {
+    Template::render(
+        "login",
+        json!({
+            "failed": failed,
+            "pending": challenges.is_pending(cookies),
+            "csrf": Csrf::token(cookies),
+            "servers": server_list(servers).await,
+        }),
+    )
+}
+
+#[post("/login", data = "<login>")]
+pub async fn login(
+    users: &State<Users>,
+    cookies: &CookieJar<'_>,
+    ip: Option<IpAddr>,
+    login: Form<Login>,
+) -> Redirect {
+    if !Csrf::verify(cookies, &login.csrf) {
+        return Redirect::to("/login?failed=csrf");
+    }
+    if users.is_throttled(ip, &login.user) {
+        info!(
+            "Refused login attempt for user {} after too many failures",
+            login.user
+        );
+        return Redirect::to("/login?failed=throttled");
+    }
+    if !users.login(cookies, ip, &login.user, &login.password).await {
+        info!("Failed login attempt for user {}", login.user);
+        return Redirect::to("/login?failed=password");
+    }
+    info!("User {} logged in", login.user);
+    Redirect::to("/")
+}
+
+#[post("/login/teamspeak", data = "<login>")]
+pub async fn teamspeak_login(
+    servers: &State<Servers>,
+    challenges: &State<Challenges>,
+    cookies: &CookieJar<'_>,
+    ip: Option<IpAddr>,
+    login: Form<TeamSpeakLogin>,
+) -> Redirect {
+    if !Csrf::verify(cookies, &login.csrf) {
+        return Redirect::to("/login?failed=csrf");
+    }
+    let Some(client) = servers.get(&login.server) else {
+        return Redirect::to("/login?failed=client");
+    };
+    if let Err(reason) = challenges.request(ip, &login.server, &login.nickname) {
+        info!("Refused to send login code to {}: {reason}", login.nickname);
+        return Redirect::to(format!("/login?failed={reason}"));
+    }
+    let code = Challenges::code();
+    let id = match client
+        .poke_client(&login.nickname, &format!("Sunbird login code: {code}"))
+        .await
+    {
+        Ok(id) => id,
+        Err(e) => {
+            info!("Could not send login code to {}: {e}", login.nickname);
+            return Redirect::to("/login?failed=client");
+        }
+    };
+    challenges.create(cookies, &login.server, id, &login.nickname, code);
+    Redirect::to("/login")
+}
+
+#[post("/login/code", data = "<code>")]
+pub async fn login_code(
+    servers: &State<Servers>,
+    challenges: &State<Challenges>,
+    cookies: &CookieJar<'_>,
+    code: Form<LoginCode>,
+) -> Redirect {
+    if !Csrf::verify(cookies, &code.csrf) {
+        return Redirect::to("/login?failed=csrf");
+    }
+    let Some(challenge) = challenges.solve(cookies, &code.code) else {
+        return Redirect::to("/login?failed=code");
+    };
+    let Some(client) = servers.get(&challenge.server) else {
+        return Redirect::to("/login?failed=client");
+    };
+    let roles = match client
+        .client_roles(challenge.client, &challenge.nickname)
+        .await
+    {
+        Ok(roles) if roles.is_empty() => {
+            info!("{} has no server group with a web role", challenge.nickname);
+            return Redirect::to("/login?failed=role");
+        }
+        Ok(roles) => roles,
+        Err(e) => {
+            info!("Could not get roles of {}: {e}", challenge.nickname);
+            return Redirect::to("/login?failed=client");
+        }
+    };
+
+    info!(
+        "{} logged in with their TeamSpeak identity on virtual server {}",
+        challenge.nickname, challenge.server
+    );
+    Session::start(
+        cookies,
+        &challenge.nickname,
+        roles,
+        Some(challenge.server.clone()),
+    );
+    Redirect::to(format!("/s/{}", challenge.server))
+}
+
+#[post("/logout", data = "<logout>")]
+pub async fn logout(cookies: &CookieJar<'_>, logout: Form<Logout>) -> Redirect {
+    if !Csrf::verify(cookies, &logout.csrf) {
+        return Redirect::to("/");
+    }
+    Session::end(cookies);
+    Redirect::to("/")
+}
+
 // =============
 // API endpoints
 // =============
 
-#[post("/augmentation/<name>/augment", format = "json", data = "<prefix>")]
+/// Answer of every successful mutation, failures are answered by [`Error`].
+#[derive(Serialize, ToSchema)]
+pub struct Success {
+    /// Always `true`.
+    pub ok: bool,
+}
+
+fn success() -> Json<Success> {
+    Json(Success { ok: true })
+}
+
+/// Decodes the URL safe base64 key of an augmentation.
+fn decode_name(name: &str) -> Result<String, Error> {
+    general_purpose::URL_SAFE_NO_PAD
+        .decode(name.as_bytes())
+        .ok()
+        .and_then(|name| String::from_utf8(name).ok())
+        .ok_or(Error::InvalidName)
+}
+
+#[utoipa::path(
+    post,
+    path = "/s/{server}/augmentation/{name}/augment",
+    params(
+        ("server" = String, Path, description = "Id of the virtual server"),
+        ("name" = String, Path, description = "URL safe base64 key `<parent id>/<identifier>` of the augmentation"),
+    ),
+    request_body = AugmentationOptions,
+    responses(
+        (status = 200, description = "Channel augmented", body = Success),
+        (status = 400, description = "Invalid name or options", body = ErrorBody),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Missing permission or invalid CSRF token", body = ErrorBody),
+        (status = 404, description = "Unknown virtual server or augmentation", body = ErrorBody),
+        (status = 409, description = "Channel is augmented already or the server is leased by another Sunbird", body = ErrorBody),
+        (status = 502, description = "ServerQuery failed", body = ErrorBody),
+    ),
+    security(("session" = [], "csrf_token" = []))
+)]
+#[post(
+    "/s/<server>/augmentation/<name>/augment",
+    format = "json",
+    data = "<options>"
+)]
 pub async fn augment(
-    client: &State<Arc<AugmentationClient>>,
+    _access: CanAugment,
+    _csrf: CsrfChecked,
+    servers: &State<Servers>,
+    server: &str,
     name: &str,
-    prefix: Json<AugmentationPrefix>,
-) -> String {
-    let name = match String::from_utf8(
-        match general_purpose::URL_SAFE_NO_PAD.decode(name.as_bytes()) {
-            Ok(name) => name,
-            Err(e) => {
-                error!("Could not decode augmentation name: {e}");
-                return e.to_string();
-            }
-        },
-    ) {
-        Ok(name) => name,
-        Err(e) => {
-            error!("Could not decode augmentation name: {e}");
-            return e.to_string();
-        }
-    };
+    options: Json<AugmentationOptions>,
+) -> Result<Json<Success>, Error> {
+    let client = servers.get(server).ok_or(Error::UnknownServer)?;
+    let name = decode_name(name)?;
 
-    if let Err(e) = client.add_augmentation(&name, prefix.into_inner()).await {
+    if let Err(e) = client.add_augmentation(&name, options.into_inner()).await {
         error!("Could not augment channel: {e}");
-        return e.to_string();
+        return Err(e);
     }
 
-    "Success".to_string()
+    Ok(success())
 }
 
-#[post("/augmentation/<name>/abridge")]
-pub async fn abridge(client: &State<Arc<AugmentationClient>>, name: &str) -> String {
-    let name = match String::from_utf8(
-        match general_purpose::URL_SAFE_NO_PAD.decode(name.as_bytes()) {
-            Ok(name) => name,
-            Err(e) => {
-                error!("Could not decode augmentation name: {e}");
-                return e.to_string();
-            }
-        },
-    ) {
-        Ok(name) => name,
-        Err(e) => {
-            error!("Could not decode augmentation name: {e}");
-            return e.to_string();
-        }
-    };
+#[utoipa::path(
+    post,
+    path = "/s/{server}/augmentation/{name}/abridge",
+    params(
+        ("server" = String, Path, description = "Id of the virtual server"),
+        ("name" = String, Path, description = "URL safe base64 key `<parent id>/<identifier>` of the augmentation"),
+    ),
+    responses(
+        (status = 200, description = "Augmentation removed", body = Success),
+        (status = 400, description = "Invalid name", body = ErrorBody),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Missing permission or invalid CSRF token", body = ErrorBody),
+        (status = 404, description = "Unknown virtual server or augmentation", body = ErrorBody),
+        (status = 409, description = "Virtual server is leased by another Sunbird", body = ErrorBody),
+        (status = 502, description = "ServerQuery failed", body = ErrorBody),
+    ),
+    security(("session" = [], "csrf_token" = []))
+)]
+#[post("/s/<server>/augmentation/<name>/abridge")]
+pub async fn abridge(
+    _access: CanAbridge,
+    _csrf: CsrfChecked,
+    servers: &State<Servers>,
+    server: &str,
+    name: &str,
+) -> Result<Json<Success>, Error> {
+    let client = servers.get(server).ok_or(Error::UnknownServer)?;
+    let name = decode_name(name)?;
 
     if let Err(e) = client.remove_augmentation(&name).await {
         error!("Could not remove augmentation: {e}");
-        return e.to_string();
+        return Err(e);
     }
 
-    "Success".to_string()
+    Ok(success())
 }
 
+#[utoipa::path(
+    post,
+    path = "/s/{server}/augmentation/{name}/resync",
+    params(
+        ("server" = String, Path, description = "Id of the virtual server"),
+        ("name" = String, Path, description = "URL safe base64 key `<parent id>/<identifier>` of the augmentation"),
+    ),
+    responses(
+        (status = 200, description = "First channel copied to all instances", body = Success),
+        (status = 400, description = "Invalid name", body = ErrorBody),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Missing permission or invalid CSRF token", body = ErrorBody),
+        (status = 404, description = "Unknown virtual server or augmentation", body = ErrorBody),
+        (status = 409, description = "Virtual server is leased by another Sunbird", body = ErrorBody),
+        (status = 502, description = "ServerQuery failed", body = ErrorBody),
+    ),
+    security(("session" = [], "csrf_token" = []))
+)]
+#[post("/s/<server>/augmentation/<name>/resync")]
+pub async fn resync(
+    _access: CanResync,
+    _csrf: CsrfChecked,
+    servers: &State<Servers>,
+    server: &str,
+    name: &str,
+) -> Result<Json<Success>, Error> {
+    let client = servers.get(server).ok_or(Error::UnknownServer)?;
+    let name = decode_name(name)?;
+
+    if let Err(e) = client.resync_augmentation(&name).await {
+        error!("Could not resync augmentation: {e}");
+        return Err(e);
+    }
+
+    Ok(success())
+}
+
+#[utoipa::path(
+    post,
+    path = "/s/{server}/augmentation/{name}/change_prefix",
+    params(
+        ("server" = String, Path, description = "Id of the virtual server"),
+        ("name" = String, Path, description = "URL safe base64 key `<parent id>/<identifier>` of the augmentation"),
+    ),
+    request_body = AugmentationOptions,
+    responses(
+        (status = 200, description = "Options changed", body = Success),
+        (status = 400, description = "Invalid name or options", body = ErrorBody),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Missing permission or invalid CSRF token", body = ErrorBody),
+        (status = 404, description = "Unknown virtual server or augmentation", body = ErrorBody),
+        (status = 409, description = "Virtual server is leased by another Sunbird", body = ErrorBody),
+        (status = 502, description = "ServerQuery failed", body = ErrorBody),
+    ),
+    security(("session" = [], "csrf_token" = []))
+)]
 #[post(
-    "/augmentation/<name>/change_prefix",
+    "/s/<server>/augmentation/<name>/change_prefix",
     format = "json",
-    data = "<prefix>"
+    data = "<options>"
 )]
 pub async fn change_prefix(
-    client: &State<Arc<AugmentationClient>>,
+    _access: CanChangePrefix,
+    _csrf: CsrfChecked,
+    servers: &State<Servers>,
+    server: &str,
     name: &str,
-    prefix: Json<AugmentationPrefix>,
-) -> String {
-    let name = match String::from_utf8(
-        match general_purpose::URL_SAFE_NO_PAD.decode(name.as_bytes()) {
-            Ok(name) => name,
-            Err(e) => {
-                error!("Could not decode augmentation name: {e}");
-                return e.to_string();
-            }
-        },
-    ) {
-        Ok(name) => name,
-        Err(e) => {
-            error!("Could not decode augmentation name: {e}");
-            return e.to_string();
-        }
-    };
+    options: Json<AugmentationOptions>,
+) -> Result<Json<Success>, Error> {
+    let client = servers.get(server).ok_or(Error::UnknownServer)?;
+    let name = decode_name(name)?;
 
     if let Err(e) = client
-        .change_augmentation_prefix(&name, prefix.into_inner())
+        .change_augmentation_options(&name, options.into_inner())
         .await
     {
         error!("Could not change augmentation prefix: {e}");
-        return e.to_string();
+        return Err(e);
     }
 
-    "Success".to_string()
+    Ok(success())
+}
+
+#[utoipa::path(
+    get,
+    path = "/s/{server}/augmentation/{name}/plan",
+    params(
+        ("server" = String, Path, description = "Id of the virtual server"),
+        ("name" = String, Path, description = "URL safe base64 key `<parent id>/<identifier>` of the augmentation"),
+    ),
+    responses(
+        (status = 200, description = "Actions that would be applied", body = Vec<Action>),
+        (status = 400, description = "Invalid name", body = ErrorBody),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Unknown virtual server or augmentation", body = ErrorBody),
+        (status = 502, description = "ServerQuery failed", body = ErrorBody),
+    ),
+    security((), ("session" = []))
+)]
+#[get("/s/<server>/augmentation/<name>/plan")]
+pub async fn plan(
+    _access: CanViewTree,
+    servers: &State<Servers>,
+    server: &str,
+    name: &str,
+) -> Result<Json<Vec<Action>>, Error> {
+    let client = servers.get(server).ok_or(Error::UnknownServer)?;
+    let name = decode_name(name)?;
+
+    match client.plan_augmentation(&name).await {
+        Ok(plan) => Ok(Json(plan)),
+        Err(e) => {
+            error!("Could not plan augmentation: {e}");
+            Err(e)
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/s/{server}/foreign/groups",
+    params(
+        ("server" = String, Path, description = "Id of the virtual server"),
+    ),
+    responses(
+        (status = 200, description = "Groups not managed by this Sunbird", body = Vec<ForeignGroup>),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Unknown virtual server", body = ErrorBody),
+        (status = 502, description = "ServerQuery failed", body = ErrorBody),
+    ),
+    security((), ("session" = []))
+)]
+#[get("/s/<server>/foreign/groups")]
+pub async fn foreign_groups(
+    _access: CanViewTree,
+    servers: &State<Servers>,
+    server: &str,
+) -> Result<Json<Vec<ForeignGroup>>, Error> {
+    let client = servers.get(server).ok_or(Error::UnknownServer)?;
+    match client.foreign_groups().await {
+        Ok(groups) => Ok(Json(groups)),
+        Err(e) => {
+            error!("Could not find foreign groups: {e}");
+            Err(e)
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/s/{server}/foreign/{channel}/adopt",
+    params(
+        ("server" = String, Path, description = "Id of the virtual server"),
+        ("channel" = i32, Path, description = "Any channel of the group"),
+    ),
+    responses(
+        (status = 200, description = "Group adopted", body = Success),
+        (status = 400, description = "Group does not follow a known pattern", body = ErrorBody),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Missing permission or invalid CSRF token", body = ErrorBody),
+        (status = 404, description = "Unknown virtual server or group", body = ErrorBody),
+        (status = 409, description = "Group is augmented already or the server is leased by another Sunbird", body = ErrorBody),
+        (status = 502, description = "ServerQuery failed", body = ErrorBody),
+    ),
+    security(("session" = [], "csrf_token" = []))
+)]
+#[post("/s/<server>/foreign/<channel>/adopt")]
+pub async fn adopt(
+    _access: CanAdopt,
+    _csrf: CsrfChecked,
+    servers: &State<Servers>,
+    server: &str,
+    channel: i32,
+) -> Result<Json<Success>, Error> {
+    let client = servers.get(server).ok_or(Error::UnknownServer)?;
+    if let Err(e) = client.adopt_group(channel).await {
+        error!("Could not adopt group: {e}");
+        return Err(e);
+    }
+
+    Ok(success())
+}
+
+#[utoipa::path(
+    get,
+    path = "/s/{server}/connection",
+    params(
+        ("server" = String, Path, description = "Id of the virtual server"),
+    ),
+    responses(
+        (status = 200, description = "State of the ServerQuery connection", body = ConnectionState),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Unknown virtual server", body = ErrorBody),
+        (status = 502, description = "ServerQuery failed", body = ErrorBody),
+    ),
+    security((), ("session" = []))
+)]
+#[get("/s/<server>/connection")]
+pub async fn connection(
+    _access: CanViewTree,
+    servers: &State<Servers>,
+    server: &str,
+) -> Result<Json<ConnectionState>, Error> {
+    let client = servers.get(server).ok_or(Error::UnknownServer)?;
+    Ok(Json(client.connection()))
 }
@@ -1,13 +1,87 @@
-use rocket::catch;
+use rocket::http::Method;
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::{catch, Request};
 use rocket_dyn_templates::Template;
 use serde_json::json;
 
+use crate::augmentation::ConnectionState;
+use crate::csrf::GuardError;
+use crate::errors::ErrorBody;
+use crate::servers::Servers;
+
+/// Error in the format of [`crate::errors::Error`], for the frontend to show.
+fn api_error(message: &str) -> Json<ErrorBody> {
+    Json(ErrorBody::new(message))
+}
+
+/// Connection of the virtual server the request is about, if any.
+fn connection(req: &Request) -> Option<ConnectionState> {
+    req.rocket()
+        .state::<Servers>()
+        .zip(req.routed_segment(1))
+        .and_then(|(servers, server)| servers.get(server))
+        .map(|client| client.connection())
+}
+
+/// Whether the request comes from a browser navigating to a page, rather than
+/// from the frontend or an API client expecting JSON.
+fn wants_page(req: &Request) -> bool {
+    req.method() == Method::Get
+        && req
+            .accept()
+            .is_some_and(|accept| accept.preferred().media_type().is_html())
+}
+
 #[catch(500)]
-pub fn internal_error() -> Template {
-    Template::render("500", json!({}))
+pub fn internal_error(req: &Request) -> Template {
+    // most errors are caused by a lost connection, which is recovered automatically
+    Template::render("500", json!({ "connection": connection(req) }))
+}
+
+#[catch(502)]
+pub fn bad_gateway(req: &Request) -> Template {
+    Template::render(
+        "500",
+        json!({ "connection": connection(req), "unavailable": true }),
+    )
+}
+
+#[catch(401)]
+pub fn unauthorized(req: &Request) -> Result<Redirect, Json<ErrorBody>> {
+    // pages lead to the login, everything else is requested by the frontend,
+    // which shows the error to the user
+    if wants_page(req) {
+        return Ok(Redirect::to("/login"));
+    }
+    Err(api_error("Please log in to do this"))
+}
+
+#[catch(403)]
+pub fn forbidden(req: &Request) -> Result<Template, Json<ErrorBody>> {
+    let reason = req.local_cache(|| GuardError("You are not allowed to do this"));
+    if wants_page(req) {
+        return Ok(Template::render("403", json!({ "reason": reason.0 })));
+    }
+    Err(api_error(reason.0))
+}
+
+#[catch(422)]
+pub fn unprocessable() -> Json<ErrorBody> {
+    api_error("Invalid request body")
 }
 
 #[catch(404)]
 pub fn not_found() -> Template {
     Template::render("404", json!({}))
 }
+
+#[catch(401)]
+pub fn api_unauthorized() -> Json<ErrorBody> {
+    api_error("Missing or invalid API token")
+}
+
+#[catch(404)]
+pub fn api_not_found() -> Json<ErrorBody> {
+    api_error("Not found")
+}
@@ -0,0 +1,35 @@
+use indexmap::IndexMap;
+use std::sync::Arc;
+
+use crate::augmentation::AugmentationClient;
+
+/// Virtual servers managed by this Sunbird, keyed by the id used in routes.
+#[derive(Default)]
+pub struct Servers(IndexMap<String, Arc<AugmentationClient>>);
+
+impl Servers {
+    pub fn insert(&mut self, server: String, client: Arc<AugmentationClient>) {
+        self.0.insert(server, client);
+    }
+
+    pub fn get(&self, server: &str) -> Option<Arc<AugmentationClient>> {
+        self.0.get(server).cloned()
+    }
+
+    /// Server used for everything that is not specific to a virtual server.
+    pub fn first(&self) -> Option<Arc<AugmentationClient>> {
+        self.0.values().next().cloned()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Arc<AugmentationClient>)> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
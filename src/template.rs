@@ -0,0 +1,447 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::augmentation::AugmentationPrefix;
+use crate::errors::Error;
+use crate::numbering::NumberingScheme;
+
+/// Maximum length of a channel name accepted by TeamSpeak.
+const MAX_NAME_LENGTH: usize = 40;
+
+/// Template describing the name of every instance of an augmentation.
+///
+/// Supported placeholders:
+///  * `{identifier}` the name of the augmented channel
+///  * `{numeral}` the position formatted by the numbering scheme
+///  * `{index}` the 1-based position as arabic number
+///  * `{total}` the number of instances
+///  * `{first:text}`, `{middle:text}`, `{last:text}` text only present on the
+///    first, a middle or the last instance (first takes precedence over last)
+///
+/// Literal braces are written as `{{` and `}}`.
+#[derive(Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(transparent)]
+pub struct NameTemplate(pub String);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Position {
+    First,
+    Middle,
+    Last,
+}
+
+enum Segment {
+    Literal(String),
+    Identifier,
+    Numeral,
+    Index,
+    Total,
+    Conditional(Position, String),
+}
+
+impl Default for NameTemplate {
+    fn default() -> Self {
+        NameTemplate("{identifier} {numeral}".to_string())
+    }
+}
+
+impl From<AugmentationPrefix> for NameTemplate {
+    fn from(prefix: AugmentationPrefix) -> Self {
+        let escape = |s: &str| s.replace('{', "{{").replace('}', "}}");
+        NameTemplate(format!(
+            "{{first:{}}}{{middle:{}}}{{last:{}}}{{identifier}} {{numeral}}",
+            escape(&prefix.first),
+            escape(&prefix.middle),
+            escape(&prefix.last)
+        ))
+    }
+}
+
+impl Position {
+    fn of(index: usize, total: usize) -> Self {
+        if index == 0 {
+            Position::First
+        } else if index + 1 == total {
+            Position::Last
+        } else {
+            Position::Middle
+        }
+    }
+}
+
+impl NameTemplate {
+    fn parse(&self) -> Result<Vec<Segment>, Error> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = self.0.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '}' => return Err(Error::Template("unmatched }".to_string())),
+                '{' => {
+                    // read the placeholder up to the closing brace
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') if chars.peek() == Some(&'}') => {
+                                chars.next();
+                                placeholder.push('}');
+                            }
+                            Some('{') if chars.peek() == Some(&'{') => {
+                                chars.next();
+                                placeholder.push('{');
+                            }
+                            Some('}') => break,
+                            Some('{') => {
+                                return Err(Error::Template("nested placeholder".to_string()))
+                            }
+                            Some(c) => placeholder.push(c),
+                            None => return Err(Error::Template("unmatched {".to_string())),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(match placeholder.split_once(':') {
+                        Some(("first", text)) => Segment::Conditional(Position::First, text.into()),
+                        Some(("middle", text)) => {
+                            Segment::Conditional(Position::Middle, text.into())
+                        }
+                        Some(("last", text)) => Segment::Conditional(Position::Last, text.into()),
+                        _ => match placeholder.as_str() {
+                            "identifier" => Segment::Identifier,
+                            "numeral" => Segment::Numeral,
+                            "index" => Segment::Index,
+                            "total" => Segment::Total,
+                            _ => {
+                                return Err(Error::Template(format!(
+                                    "unknown placeholder {{{placeholder}}}"
+                                )))
+                            }
+                        },
+                    });
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(segments)
+    }
+
+    /// Name of the instance at `index` (0-based) in a group of `total` instances.
+    pub fn render(
+        &self,
+        identifier: &str,
+        numbering: &NumberingScheme,
+        index: usize,
+        total: usize,
+    ) -> Result<String, Error> {
+        let position = Position::of(index, total);
+        let mut name = String::new();
+        for segment in self.parse()? {
+            match segment {
+                Segment::Literal(text) => name.push_str(&text),
+                Segment::Identifier => name.push_str(identifier),
+                Segment::Numeral => {
+                    name.push_str(&numbering.format(index + 1).ok_or_else(|| {
+                        Error::Numbering(format!("cannot represent instance {}", index + 1))
+                    })?)
+                }
+                Segment::Index => name.push_str(&(index + 1).to_string()),
+                Segment::Total => name.push_str(&total.to_string()),
+                Segment::Conditional(p, text) => {
+                    if p == position {
+                        name.push_str(&text);
+                    }
+                }
+            }
+        }
+        Ok(name)
+    }
+
+    /// Compiles the template into a regex matching every name it can render.
+    pub fn to_regex(&self, identifier: &str, numbering: &NumberingScheme) -> Result<Regex, Error> {
+        let segments = self.parse()?;
+        let mut pattern = String::from("^");
+        let mut i = 0;
+        while i < segments.len() {
+            match &segments[i] {
+                Segment::Literal(text) => pattern.push_str(&regex::escape(text)),
+                Segment::Identifier => pattern.push_str(&regex::escape(identifier)),
+                Segment::Numeral => pattern.push_str(&numbering.pattern()),
+                Segment::Index | Segment::Total => pattern.push_str("[1-9][0-9]*"),
+                Segment::Conditional(..) => {
+                    // a run of conditionals renders the concatenation of the texts
+                    // belonging to the position, so match exactly one of those
+                    let mut texts = [String::new(), String::new(), String::new()];
+                    while let Some(Segment::Conditional(position, text)) = segments.get(i) {
+                        texts[*position as usize].push_str(text);
+                        i += 1;
+                    }
+                    let mut alternatives =
+                        texts.iter().map(|t| regex::escape(t)).collect::<Vec<_>>();
+                    alternatives.sort();
+                    alternatives.dedup();
+                    pattern.push_str(&format!("(?:{})", alternatives.join("|")));
+                    continue;
+                }
+            }
+            i += 1;
+        }
+        pattern.push('$');
+        Ok(Regex::new(&pattern)?)
+    }
+
+    /// Ensures the template generates distinct names that are recognized by its
+    /// own matcher, so the instances are not lost after renaming.
+    pub fn validate(&self, identifier: &str, numbering: &NumberingScheme) -> Result<(), Error> {
+        let segments = self.parse()?;
+        if !segments
+            .iter()
+            .any(|s| matches!(s, Segment::Numeral | Segment::Index))
+        {
+            return Err(Error::Template(
+                "template must contain {numeral} or {index}".to_string(),
+            ));
+        }
+
+        let regex = self.to_regex(identifier, numbering)?;
+        for total in [1, 2, 3, 4, 10] {
+            let mut names = Vec::new();
+            for index in 0..total {
+                let name = match self.render(identifier, numbering, index, total) {
+                    Ok(name) => name,
+                    // the numbering scheme may not cover larger groups
+                    Err(Error::Numbering(_)) if index > 0 => break,
+                    Err(e) => return Err(e),
+                };
+                if !regex.is_match(&name) {
+                    return Err(Error::Template(format!(
+                        "generated name {name} is not recognized by the template"
+                    )));
+                }
+                if name.chars().count() > MAX_NAME_LENGTH {
+                    return Err(Error::Template(format!(
+                        "generated name {name} is longer than {MAX_NAME_LENGTH} characters"
+                    )));
+                }
+                if names.contains(&name) {
+                    return Err(Error::Template(format!(
+                        "generated name {name} is not unique"
+                    )));
+                }
+                names.push(name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Infers the identifier and template of a group named like
+    /// `<prefix><identifier> <roman numeral>`, as long as the first, middle and
+    /// last channels share their prefixes.
+    pub fn infer(names: &[&str]) -> Option<(String, NameTemplate)> {
+        if names.len() < 2 {
+            return None;
+        }
+        let stems = names
+            .iter()
+            .map(|n| {
+                n.trim_end_matches(|c| "IVXLCDM".contains(c))
+                    .strip_suffix(' ')
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        // the identifier is the common end of all stems, without decorations
+        let mut identifier = stems[0];
+        for stem in &stems[1..] {
+            while !stem.ends_with(identifier) {
+                identifier = &identifier[identifier.chars().next()?.len_utf8()..];
+            }
+        }
+        let identifier = identifier.trim_start_matches(|c: char| !c.is_alphanumeric());
+        if identifier.is_empty() {
+            return None;
+        }
+
+        let prefixes = stems
+            .iter()
+            .map(|s| &s[..s.len() - identifier.len()])
+            .collect::<Vec<_>>();
+        let last = prefixes[prefixes.len() - 1];
+        let middle = prefixes[1..prefixes.len() - 1]
+            .first()
+            .copied()
+            .unwrap_or(last);
+        if prefixes[1..prefixes.len() - 1].iter().any(|p| *p != middle) {
+            return None;
+        }
+        let template = NameTemplate::from(AugmentationPrefix {
+            first: prefixes[0].to_string(),
+            middle: middle.to_string(),
+            last: last.to_string(),
+        });
+
+        let regex = template
+            .to_regex(identifier, &NumberingScheme::Roman)
+            .ok()?;
+        names
+            .iter()
+            .all(|n| regex.is_match(n))
+            .then(|| (identifier.to_string(), template))
+    }
+
+    /// Whether names depend on the size of the group.
+    pub fn uses_total(&self) -> bool {
+        self.parse()
+            .map(|segments| segments.iter().any(|s| matches!(s, Segment::Total)))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_all(
+        template: &NameTemplate,
+        numbering: &NumberingScheme,
+        total: usize,
+    ) -> Vec<String> {
+        (0..total)
+            .map(|i| template.render("Lobby", numbering, i, total).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn conditionals_round_trip() {
+        let template = NameTemplate("{first:┏ }{middle:┣ }{last:┗ }{identifier} {numeral}".into());
+        let numbering = NumberingScheme::Roman;
+        let regex = template.to_regex("Lobby", &numbering).unwrap();
+        assert_eq!(
+            render_all(&template, &numbering, 3),
+            ["┏ Lobby I", "┣ Lobby II", "┗ Lobby III"]
+        );
+        // first takes precedence over last
+        assert_eq!(render_all(&template, &numbering, 1), ["┏ Lobby I"]);
+        for total in 1..=4 {
+            for name in render_all(&template, &numbering, total) {
+                assert!(regex.is_match(&name), "{name} is not matched");
+            }
+        }
+        assert!(!regex.is_match("Lobby I"));
+        assert!(!regex.is_match("┏ ┗ Lobby I"));
+        assert!(template.validate("Lobby", &numbering).is_ok());
+    }
+
+    #[test]
+    fn braces_are_escaped() {
+        let template = NameTemplate("{{ {identifier} }} #{index}".into());
+        let numbering = NumberingScheme::Arabic;
+        assert_eq!(
+            render_all(&template, &numbering, 2),
+            ["{ Lobby } #1", "{ Lobby } #2"]
+        );
+        let regex = template.to_regex("Lobby", &numbering).unwrap();
+        assert!(regex.is_match("{ Lobby } #2"));
+        assert!(!regex.is_match("Lobby #2"));
+
+        // inside a placeholder doubled braces are part of its text
+        let template = NameTemplate("{first:{{}{last:}}}{identifier} {numeral}".into());
+        assert_eq!(
+            render_all(&template, &numbering, 3),
+            ["{Lobby 1", "Lobby 2", "}Lobby 3"]
+        );
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        let numbering = NumberingScheme::Arabic;
+        for template in [
+            "{identifier",
+            "{identifier}}",
+            "{{identifier} {numeral}",
+            "{name} {numeral}",
+        ] {
+            assert!(
+                NameTemplate(template.into())
+                    .validate("Lobby", &numbering)
+                    .is_err(),
+                "{template} is accepted"
+            );
+        }
+        // without a numeral all instances would share their name
+        let template = NameTemplate("{identifier} {total}".into());
+        assert!(template.validate("Lobby", &numbering).is_err());
+    }
+
+    #[test]
+    fn legacy_prefix_is_migrated() {
+        let template = NameTemplate::from(AugmentationPrefix {
+            first: "┏ ".to_string(),
+            middle: "{ ".to_string(),
+            last: "} ".to_string(),
+        });
+        let numbering = NumberingScheme::Roman;
+        assert_eq!(
+            render_all(&template, &numbering, 3),
+            ["┏ Lobby I", "{ Lobby II", "} Lobby III"]
+        );
+        assert!(template.validate("Lobby", &numbering).is_ok());
+
+        let template = NameTemplate::from(AugmentationPrefix {
+            first: String::new(),
+            middle: String::new(),
+            last: String::new(),
+        });
+        assert_eq!(
+            render_all(&template, &numbering, 2),
+            ["Lobby I", "Lobby II"]
+        );
+    }
+
+    #[test]
+    fn group_is_inferred() {
+        let names = ["╔ Talk I", "╠ Talk II", "╠ Talk III", "╚ Talk IV"];
+        let (identifier, template) = NameTemplate::infer(&names).unwrap();
+        assert_eq!(identifier, "Talk");
+        let numbering = NumberingScheme::Roman;
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(
+                template.render(&identifier, &numbering, i, 4).unwrap(),
+                *name
+            );
+        }
+
+        let (identifier, template) =
+            NameTemplate::infer(&["Gaming Room I", "Gaming Room II"]).unwrap();
+        assert_eq!(identifier, "Gaming Room");
+        assert_eq!(
+            template.render(&identifier, &numbering, 1, 2).unwrap(),
+            "Gaming Room II"
+        );
+    }
+
+    #[test]
+    fn unrelated_names_are_not_inferred() {
+        assert!(NameTemplate::infer(&["Lobby I"]).is_none());
+        assert!(NameTemplate::infer(&["Lobby I", "Lobby 2"]).is_none());
+        assert!(NameTemplate::infer(&["Lobby I", "Music II"]).is_none());
+        // middle channels must share their prefix
+        assert!(
+            NameTemplate::infer(&["┏ Lobby I", "┣ Lobby II", "┃ Lobby III", "┗ Lobby IV"])
+                .is_none()
+        );
+    }
+}
@@ -8,6 +8,7 @@ use ts3_query_api::{
     definitions::builder::{ChannelListFlags, ClientListFlags},
     QueryClient,
 };
+use utoipa::ToSchema;
 
 use crate::{
     augmentation::Augmentation,
@@ -15,7 +16,7 @@ use crate::{
     helper::{Channel, Client},
 };
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone, ToSchema)]
 pub struct Tree {
     pub server_name: String,
     pub channel_order: Vec<i32>,
@@ -108,7 +109,7 @@ pub async fn build_tree(
 
     // add augmentation functionality to the treeitems
     for augmented_channel in augmentations.iter() {
-        let mut rng: Pcg64 = Seeder::from(augmented_channel.identifier.as_bytes()).make_rng();
+        let mut rng: Pcg64 = Seeder::from(augmented_channel.key().as_bytes()).make_rng();
         // generate a random color for each augmentation group
         let color = format!(
             "hsl({}, {}%, {}%)",
@@ -118,9 +119,12 @@ pub async fn build_tree(
         );
 
         for channel in channel_map.values_mut() {
-            if augmented_channel.is_instance(&channel.name) {
+            if (channel.parent_id == augmented_channel.parent
+                && augmented_channel.channels.contains(&channel.id))
+                || augmented_channel.taken_over == Some(channel.id)
+            {
                 channel.is_augmented = true;
-                channel.augmentation_id = Some(augmented_channel.identifier.clone());
+                channel.augmentation_id = Some(augmented_channel.key());
                 channel.highlight_color = color.clone().into();
             }
         }
@@ -1,66 +1,321 @@
+function numbering(form) {
+  let type = form.numbering.value;
+  let argument = form.numbering_argument.value;
+  if (type == "zero_padded") {
+    return { zero_padded: parseInt(argument) || 2 };
+  }
+  if (type == "words") {
+    return {
+      words: argument.split(/[,\/]/).map(word => word.trim()).filter(word => word.length > 0)
+    };
+  }
+  return type;
+}
+
+/**
+ * Sends a mutation with the CSRF token of the page and tells if it succeeded.
+ * Failures are answered with `{"ok": false, "error": "..."}` and shown. The
+ * tree does not need to be reloaded, its changes arrive live.
+ */
+async function post(url, body) {
+  let headers = {
+    'X-CSRF-Token': document.querySelector('meta[name="csrf-token"]').content,
+  };
+  if (body !== undefined) {
+    headers['Content-Type'] = 'application/json';
+  }
+  let response;
+  try {
+    response = await fetch(url, {
+      method: 'POST',
+      body: body === undefined ? undefined : JSON.stringify(body),
+      headers: headers,
+    });
+  } catch (error) {
+    alert(`Could not reach Sunbird: ${error}`);
+    return false;
+  }
+  let result = await response.json().catch(() => ({}));
+  if (!response.ok || !result.ok) {
+    alert(result.error || `Request failed with status ${response.status}`);
+    return false;
+  }
+  return true;
+}
+
+/** Shows a short confirmation that disappears on its own. */
+function flash(message) {
+  let element = document.createElement('div');
+  element.className = 'flash';
+  element.textContent = message;
+  document.body.append(element);
+  setTimeout(() => element.remove(), 3000);
+}
+
+function augmentation_options(form) {
+  return {
+    template: form.template.value,
+    numbering: numbering(form),
+    min_empty: parseInt(form.min_empty.value),
+    max_empty: parseInt(form.max_empty.value),
+    min_instances: parseInt(form.min_instances.value) || 0,
+    max_instances: parseInt(form.max_instances.value) || null,
+    sync: form.sync.checked,
+    compaction: form.compaction.value,
+  };
+}
+
 async function augment(event) {
   event.preventDefault();
   let form = event.target;
-  await fetch(`/augmentation/${form.channel.value}/augment`, {
-    method: 'POST',
-    body: JSON.stringify({
-      first: form.first_prefix.value,
-      middle: form.middle_prefix.value,
-      last: form.last_prefix.value,
-    }),
-    headers: {
-      'Content-Type': 'application/json'
-    }
-  }).then(response => response.text()).then(text => {
-    if (text != "Success") {
-      alert(text);
-    }
-    window.location.reload();
-  });
+  let url = `/s/${form.server.value}/augmentation/${form.channel.value}`;
+  if (await post(`${url}/augment`, augmentation_options(form))) {
+    // the channel is an augmentation now, which has a page of its own
+    window.location.assign(url);
+  }
 }
 
 async function change_prefix(event) {
   event.preventDefault();
   let form = event.target;
-  await fetch(`/augmentation/${form.channel.value}/change_prefix`, {
-    method: 'POST',
-    body: JSON.stringify({
-      first: form.first_prefix.value,
-      middle: form.middle_prefix.value,
-      last: form.last_prefix.value,
-    }),
-    headers: {
-      'Content-Type': 'application/json'
-    }
-  }).then(response => response.text()).then(text => {
-    if (text != "Success") {
-      alert(text);
-    }
-    window.location.reload();
-  });
+  if (await post(
+    `/s/${form.server.value}/augmentation/${form.channel.value}/change_prefix`,
+    augmentation_options(form)
+  )) {
+    flash('Augmentation updated');
+  }
 }
 
 async function update_augmentation(event) {
   event.preventDefault();
-  console.log(event);
   let type = event.submitter.name;
   if (type == "update") {
     await change_prefix(event);
   }
+  else if (type == "resync") {
+    await resync_augmentation(event);
+  }
   else if (type == "abridge") {
     await abridge_augmentation(event);
   }
 }
 
+async function resync_augmentation(event) {
+  event.preventDefault();
+  let server = event.target.server.value;
+  let channel = event.target.channel.value;
+  if (await post(`/s/${server}/augmentation/${channel}/resync`)) {
+    flash('Channels resynced');
+  }
+}
+
 async function abridge_augmentation(event) {
   event.preventDefault();
+  let server = event.target.server.value;
   let channel = event.target.channel.value;
-  await fetch(`/augmentation/${channel}/abridge`, {
-    method: 'POST',
-  }).then(response => response.text()).then(text => {
-    if (text != "Success") {
-      alert(text);
+  let url = `/s/${server}/augmentation/${channel}`;
+  if (await post(`${url}/abridge`)) {
+    // leads back to the page of the channel
+    window.location.assign(url);
+  }
+}
+
+async function adopt_group(button) {
+  let { server, channel } = button.dataset;
+  if (await post(`/s/${server}/foreign/${channel}/adopt`)) {
+    button.closest('.group').remove();
+    flash('Group adopted');
+  }
+}
+
+// ==========
+// LIVE TREE
+// ==========
+
+function escape_html(text) {
+  let element = document.createElement('span');
+  element.textContent = text;
+  return element.innerHTML;
+}
+
+/** URL safe base64 without padding, like the `base64_encode` filter. */
+function base64_encode(value) {
+  let bytes = new TextEncoder().encode(value);
+  return btoa(String.fromCharCode(...bytes))
+    .replace(/\+/g, '-')
+    .replace(/\//g, '_')
+    .replace(/=+$/, '');
+}
+
+function element_of(html) {
+  let template = document.createElement('template');
+  template.innerHTML = html.trim();
+  let element = template.content.firstElementChild;
+  if (window.twemoji) {
+    twemoji.parse(element, { folder: 'svg', ext: '.svg' });
+  }
+  return element;
+}
+
+// the functions below mirror the macros in tree.html.tera
+
+function render_channel(server, channel) {
+  if (/^\[c?spacer\]/.test(channel.name) && channel.indent_level == 0) {
+    return element_of(`
+      <a class="tree_item spacer${/^\[cspacer\]/.test(channel.name) ? ' center' : ''}"
+        data-channel="${channel.id}" data-indent="${channel.indent_level}"
+        href="/s/${server}/channel/${channel.id}">
+        <div class="highlight"></div>
+        <div class="wrapper">
+          <p class="segoe_fix spread">
+            <span>${escape_html(channel.name.replace(/^\[c?spacer\]\s*/, ''))}</span>
+          </p>
+        </div>
+      </a>`);
+  }
+  let href = channel.is_augmented
+    ? `/s/${server}/augmentation/${base64_encode(channel.augmentation_id)}`
+    : `/s/${server}/channel/${channel.id}`;
+  return element_of(`
+    <a class="tree_item channel${channel.is_augmented ? ' augmented' : ''}"
+      data-channel="${channel.id}" data-indent="${channel.indent_level}"
+      style="margin-left: ${channel.indent_level * 10}px"
+      href="${href}">
+      <div class="background"></div>
+      <div class="highlight"${channel.is_augmented ? ` style="border: 1px solid ${channel.highlight_color}"` : ''}></div>
+      <div class="wrapper">
+        <img class="icon" src="/static/channel.svg" height="22px" />
+        <p class="segoe_fix spread">
+          <span>${escape_html(channel.name)}</span>
+        </p>
+        ${channel.talk_power > 0 ? '<p class="segoe_fix moderated"><span>M</span></p>' : ''}
+      </div>
+    </a>`);
+}
+
+function render_client(server, client, indent) {
+  let icon = client.is_query ? 'query' : client.can_talk ? 'client_talk' : 'client_shush';
+  let icons = '';
+  if (client.badges.length > 0 || client.country) {
+    icons = `
+      <div class="icons">
+        ${client.badges.map(badge => `<img src="/badges/${escape_html(badge)}.svg" width="16px" />`).join('')}
+        ${client.badges.length > 0 && client.country ? '<div class="separator"></div>' : ''}
+        ${client.country ? `<span class="flag">${escape_html(client.country)}</span>` : ''}
+      </div>`;
+  }
+  return element_of(`
+    <a class="tree_item client"
+      data-client="${client.id}"
+      style="margin-left: ${(indent + 1) * 10}px"
+      href="/s/${server}/client/${client.id}">
+      <div class="highlight"></div>
+      <div class="wrapper">
+        <img class="icon" src="/static/${icon}.svg" width="22px" />
+        <p class="segoe_fix spread">
+          <span>${escape_html(client.name)}</span>
+        </p>
+        ${icons}
+      </div>
+    </a>`);
+}
+
+function render_tree(element, tree) {
+  let server = element.dataset.server;
+  element.querySelector('.tree_item.server span').textContent = tree.server_name;
+  element.querySelectorAll('[data-channel], [data-client]').forEach(item => item.remove());
+  for (let id of tree.channel_order) {
+    let channel = tree.channel_map[id];
+    element.append(render_channel(server, channel));
+    for (let client of tree.clients[id] || []) {
+      element.append(render_client(server, client, channel.indent_level));
+    }
+  }
+}
+
+/** Last item of a channel, the channel itself or its last client. */
+function end_of_channel(channel) {
+  let end = channel;
+  while (end.nextElementSibling && end.nextElementSibling.matches('[data-client]')) {
+    end = end.nextElementSibling;
+  }
+  return end;
+}
+
+function insert_client(element, client, index) {
+  let channel = element.querySelector(`[data-channel="${client.channel}"]`);
+  if (!channel) {
+    return;
+  }
+  let item = render_client(element.dataset.server, client, parseInt(channel.dataset.indent));
+  let before = channel;
+  for (let i = 0; i < index && before.nextElementSibling?.matches('[data-client]'); i++) {
+    before = before.nextElementSibling;
+  }
+  before.after(item);
+}
+
+/**
+ * Applies a batch of changes pushed by Sunbird. Everything that is redrawn is
+ * removed first, so the positions of the changes only count unchanged items.
+ */
+function apply_changes(element, changes) {
+  let server = element.dataset.server;
+  let remove = selector => element.querySelectorAll(selector).forEach(item => item.remove());
+  for (let change of changes) {
+    switch (change.change) {
+      case 'client_joined':
+      case 'client_moved':
+      case 'client_updated':
+        remove(`[data-client="${change.client.id}"]`);
+        break;
+      case 'client_left':
+        remove(`[data-client="${change.id}"]`);
+        break;
+      case 'channel_deleted':
+        remove(`[data-channel="${change.id}"]`);
+        break;
     }
-    window.location.reload();
-  });
-}
\ No newline at end of file
+  }
+  for (let change of changes) {
+    switch (change.change) {
+      case 'reset':
+        render_tree(element, change.tree);
+        break;
+      case 'server_renamed':
+        element.querySelector('.tree_item.server span').textContent = change.name;
+        break;
+      case 'channel_created': {
+        remove(`[data-channel="${change.channel.id}"]`);
+        let after = change.after === null
+          ? element.querySelector('.tree_item.server')
+          : element.querySelector(`[data-channel="${change.after}"]`);
+        if (after) {
+          end_of_channel(after).after(render_channel(server, change.channel));
+        }
+        break;
+      }
+      case 'channel_updated':
+        element.querySelector(`[data-channel="${change.channel.id}"]`)
+          ?.replaceWith(render_channel(server, change.channel));
+        break;
+      case 'client_joined':
+      case 'client_moved':
+      case 'client_updated':
+        insert_client(element, change.client, change.index);
+        break;
+    }
+  }
+}
+
+/** Subscribes to the changes of the tree, which start with the current tree. */
+function watch_tree() {
+  let element = document.getElementById('tree');
+  if (!element || !element.dataset.server) {
+    return;
+  }
+  let events = new EventSource(`/s/${element.dataset.server}/events`);
+  events.onmessage = event => apply_changes(element, JSON.parse(event.data));
+}
+
+window.addEventListener('DOMContentLoaded', watch_tree);
\ No newline at end of file